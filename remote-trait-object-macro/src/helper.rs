@@ -1,3 +1,4 @@
+pub mod attribute;
 pub mod dispatcher;
 pub mod id;
 pub mod remote;
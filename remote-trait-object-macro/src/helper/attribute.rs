@@ -0,0 +1,196 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::id::IdScheme;
+use crate::create_env_path;
+use proc_macro2::TokenStream as TokenStream2;
+use syn::parse::Parser;
+
+/// Arguments of `#[service(...)]`.
+#[derive(Default)]
+pub struct ServiceArgs {
+    pub id_scheme: IdScheme,
+    /// Generates `FooAsyncRemote` too. The `async` feature of remote-trait-object is needed for it.
+    pub async_remote: bool,
+    /// Generates `FooAsync`, which an async service object implements, and its dispatcher.
+    /// The `async` feature of remote-trait-object is needed for it.
+    pub async_dispatch: bool,
+    /// Variant of `Format` that the calls of the trait take, regardless of the context.
+    pub codec: Option<syn::Ident>,
+}
+
+impl ServiceArgs {
+    pub fn parse(args: TokenStream2) -> Result<Self, TokenStream2> {
+        let mut result = ServiceArgs::default();
+        let metas = syn::punctuated::Punctuated::<syn::Meta, syn::Token![,]>::parse_terminated
+            .parse2(args)
+            .map_err(|e| e.to_compile_error())?;
+        for meta in metas {
+            match &meta {
+                syn::Meta::NameValue(syn::MetaNameValue {
+                    path,
+                    lit: syn::Lit::Str(value),
+                    ..
+                }) if path.is_ident("id_scheme") => {
+                    result.id_scheme = match value.value().as_str() {
+                        "order" => IdScheme::Order,
+                        "hash" => IdScheme::Hash,
+                        _ => {
+                            return Err(syn::Error::new_spanned(value, "id_scheme must be either \"order\" or \"hash\"")
+                                .to_compile_error())
+                        }
+                    }
+                }
+                syn::Meta::NameValue(syn::MetaNameValue {
+                    path,
+                    lit: syn::Lit::Str(value),
+                    ..
+                }) if path.is_ident("codec") => {
+                    let variant = match value.value().as_str() {
+                        "cbor" => "Cbor",
+                        "bincode" => "Bincode",
+                        "json" => "Json",
+                        _ => {
+                            return Err(syn::Error::new_spanned(
+                                value,
+                                "codec must be one of \"cbor\", \"bincode\" and \"json\"",
+                            )
+                            .to_compile_error())
+                        }
+                    };
+                    result.codec = Some(syn::Ident::new(variant, value.span()));
+                }
+                syn::Meta::Path(path) if path.is_ident("async_remote") => result.async_remote = true,
+                syn::Meta::Path(path) if path.is_ident("async_dispatch") => result.async_dispatch = true,
+                _ => return Err(syn::Error::new_spanned(meta, "Unknown argument of #[service]").to_compile_error()),
+            }
+        }
+        Ok(result)
+    }
+
+    /// `Format` of the trait, or `otherwise` if the trait doesn't specify it.
+    /// It names the `Codec`, so a codec whose feature is disabled fails to compile.
+    pub fn format_or(&self, otherwise: TokenStream2) -> TokenStream2 {
+        let env_path = create_env_path();
+        match &self.codec {
+            Some(variant) => quote! {<#env_path::codec::#variant as #env_path::Codec>::FORMAT},
+            None => otherwise,
+        }
+    }
+}
+
+/// Method attributes that only the macro understands.
+/// They must be removed from the trait before it is emitted.
+const METHOD_ATTRIBUTES: &[&str] = &["timeout_ms", "oneway"];
+
+/// Reads `#[timeout_ms = N]` of the method.
+pub fn timeout_ms(method: &syn::TraitItemMethod) -> Result<Option<u64>, TokenStream2> {
+    let mut result = None;
+    for attr in method.attrs.iter().filter(|attr| attr.path.is_ident("timeout_ms")) {
+        let invalid = || syn::Error::new_spanned(attr, "Use #[timeout_ms = N]").to_compile_error();
+        if result.is_some() {
+            return Err(syn::Error::new_spanned(attr, "Duplicated #[timeout_ms]").to_compile_error())
+        }
+        match attr.parse_meta().map_err(|_| invalid())? {
+            syn::Meta::NameValue(syn::MetaNameValue {
+                lit: syn::Lit::Int(x),
+                ..
+            }) => result = Some(x.base10_parse::<u64>().map_err(|e| e.to_compile_error())?),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(result)
+}
+
+/// Reads `#[oneway]` of the method, which must return nothing.
+pub fn is_oneway(method: &syn::TraitItemMethod) -> Result<bool, TokenStream2> {
+    let attr = match method.attrs.iter().find(|attr| attr.path.is_ident("oneway")) {
+        Some(attr) => attr,
+        None => return Ok(false),
+    };
+    if !attr.tokens.is_empty() {
+        return Err(syn::Error::new_spanned(attr, "Use #[oneway] without arguments").to_compile_error())
+    }
+    let returns_nothing = match &method.sig.output {
+        syn::ReturnType::Default => true,
+        syn::ReturnType::Type(_, ty) => matches!(&**ty, syn::Type::Tuple(tuple) if tuple.elems.is_empty()),
+    };
+    if !returns_nothing {
+        return Err(
+            syn::Error::new_spanned(&method.sig.output, "#[oneway] method must return nothing").to_compile_error()
+        )
+    }
+    if timeout_ms(method)?.is_some() {
+        return Err(syn::Error::new_spanned(attr, "#[oneway] method doesn't wait, so it can't have a timeout")
+            .to_compile_error())
+    }
+    Ok(true)
+}
+
+pub fn strip_method_attributes(source_trait: &mut syn::ItemTrait) {
+    for item in source_trait.items.iter_mut() {
+        if let syn::TraitItem::Method(method) = item {
+            method.attrs.retain(|attr| !METHOD_ATTRIBUTES.iter().any(|name| attr.path.is_ident(name)));
+        }
+    }
+}
+
+#[test]
+fn parse_service_args() {
+    assert_eq!(ServiceArgs::parse(quote! {}).unwrap().id_scheme, IdScheme::Order);
+    assert_eq!(ServiceArgs::parse(quote! {id_scheme = "hash"}).unwrap().id_scheme, IdScheme::Hash);
+    assert!(ServiceArgs::parse(quote! {id_scheme = "random"}).is_err());
+    assert!(ServiceArgs::parse(quote! {unknown}).is_err());
+    assert!(!ServiceArgs::parse(quote! {}).unwrap().async_remote);
+    let args = ServiceArgs::parse(quote! {async_remote, id_scheme = "hash"}).unwrap();
+    assert!(args.async_remote);
+    assert_eq!(args.id_scheme, IdScheme::Hash);
+    assert!(!args.async_dispatch);
+    assert!(ServiceArgs::parse(quote! {async_dispatch}).unwrap().async_dispatch);
+    assert!(ServiceArgs::parse(quote! {}).unwrap().codec.is_none());
+    assert_eq!(ServiceArgs::parse(quote! {codec = "json"}).unwrap().codec.unwrap(), "Json");
+    assert!(ServiceArgs::parse(quote! {codec = "xml"}).is_err());
+}
+
+#[test]
+fn parse_timeout() {
+    let method = syn::parse_str::<syn::TraitItemMethod>("#[timeout_ms = 30] fn f(&self);").unwrap();
+    assert_eq!(timeout_ms(&method).unwrap(), Some(30));
+    let method = syn::parse_str::<syn::TraitItemMethod>("fn f(&self);").unwrap();
+    assert_eq!(timeout_ms(&method).unwrap(), None);
+    let method = syn::parse_str::<syn::TraitItemMethod>("#[timeout_ms(30)] fn f(&self);").unwrap();
+    assert!(timeout_ms(&method).is_err());
+
+    let mut the_trait =
+        syn::parse_str::<syn::ItemTrait>("trait A { #[timeout_ms = 30] #[doc = \"f\"] fn f(&self); }").unwrap();
+    strip_method_attributes(&mut the_trait);
+    match &the_trait.items[0] {
+        syn::TraitItem::Method(method) => assert_eq!(method.attrs.len(), 1),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn parse_oneway() {
+    let method = syn::parse_str::<syn::TraitItemMethod>("#[oneway] fn f(&self, a: u32);").unwrap();
+    assert!(is_oneway(&method).unwrap());
+    let method = syn::parse_str::<syn::TraitItemMethod>("fn f(&self, a: u32);").unwrap();
+    assert!(!is_oneway(&method).unwrap());
+    let method = syn::parse_str::<syn::TraitItemMethod>("#[oneway] fn f(&self) -> u32;").unwrap();
+    assert!(is_oneway(&method).is_err());
+    let method = syn::parse_str::<syn::TraitItemMethod>("#[oneway] #[timeout_ms = 1] fn f(&self);").unwrap();
+    assert!(is_oneway(&method).is_err());
+}
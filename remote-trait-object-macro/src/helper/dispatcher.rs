@@ -14,10 +14,11 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use super::attribute::ServiceArgs;
 use crate::create_env_path;
 use proc_macro2::{Span, TokenStream as TokenStream2};
 
-pub fn generate_dispatcher(source_trait: &syn::ItemTrait) -> Result<TokenStream2, TokenStream2> {
+pub fn generate_dispatcher(source_trait: &syn::ItemTrait, args: &ServiceArgs) -> Result<TokenStream2, TokenStream2> {
     let env_path = create_env_path();
     let trait_ident = source_trait.ident.clone();
     let struct_ident = quote::format_ident!("{}Dispatcher", trait_ident);
@@ -26,8 +27,13 @@ pub fn generate_dispatcher(source_trait: &syn::ItemTrait) -> Result<TokenStream2
     // then introduce a closure list for the method dispatch,
     // instead of if-else clauses
     let mut if_else_clauses = TokenStream2::new();
+    // Ids are decided when the dispatcher is created, by the port that it is exported to.
+    let mut default_ids = TokenStream2::new();
+    let mut port_ids = TokenStream2::new();
+    let mut method_names = TokenStream2::new();
+    let lit_trait_name = syn::LitStr::new(&trait_ident.to_string(), Span::call_site());
 
-    for item in source_trait.items.iter() {
+    for (i, item) in source_trait.items.iter().enumerate() {
         let method = match item {
             syn::TraitItem::Method(x) => x,
             non_method => {
@@ -37,6 +43,10 @@ pub fn generate_dispatcher(source_trait: &syn::ItemTrait) -> Result<TokenStream2
             }
         };
         let id_ident = super::id::id_method_ident(source_trait, method);
+        let lit_method_name = syn::LitStr::new(&method.sig.ident.to_string(), Span::call_site());
+        default_ids.extend(quote! {#id_ident.load(#env_path::ID_ORDERING),});
+        port_ids.extend(quote! {port.local_method_id(#lit_trait_name, #lit_method_name),});
+        method_names.extend(quote! {#lit_method_name,});
 
         // Argument will be represented as a tuple. We deserialize the data as a tuple here
         let mut the_let_pattern = syn::PatTuple {
@@ -108,8 +118,7 @@ pub fn generate_dispatcher(source_trait: &syn::ItemTrait) -> Result<TokenStream2
         }
 
         let stmt_deserialize = quote! {
-            // TODO: Make the macro be able to take deserialization scheme
-            let #the_let_pattern: #type_annotation = serde_cbor::from_slice(args).unwrap();
+            let #the_let_pattern: #type_annotation = self.format.decode(args)?;
         };
 
         let method_name = method.sig.ident.clone();
@@ -118,11 +127,11 @@ pub fn generate_dispatcher(source_trait: &syn::ItemTrait) -> Result<TokenStream2
         };
 
         let the_return = quote! {
-            return serde_cbor::to_vec(&result).unwrap();
+            return self.format.encode_into(&result, &mut *response);
         };
 
         if_else_clauses.extend(quote! {
-            if method == #id_ident.load(#env_path::ID_ORDERING) {
+            if method == self.ids[#i] {
                 #stmt_deserialize
                 #stmt_call
                 #the_return
@@ -133,25 +142,161 @@ pub fn generate_dispatcher(source_trait: &syn::ItemTrait) -> Result<TokenStream2
         panic!("Invalid remote-trait-object call. Fatal Error.")
     });
 
+    let number_of_methods = source_trait.items.len();
+    let default_format = args.format_or(quote! {#env_path::Format::Cbor});
+    let port_format = args.format_or(quote! {port.format()});
     Ok(quote! {
         pub struct #struct_ident {
-            object: std::sync::Arc<dyn #trait_ident>
+            object: std::sync::Arc<dyn #trait_ident>,
+            ids: [#env_path::MethodId; #number_of_methods],
+            format: #env_path::Format,
         }
         impl #struct_ident {
+            /// Dispatches with the default ids of the process.
+            #[allow(dead_code)]
             fn new(object: std::sync::Arc<dyn #trait_ident>) -> Self {
                 Self {
-                    object
+                    object,
+                    ids: [#default_ids],
+                    format: #default_format,
+                }
+            }
+            /// Dispatches with the ids of the port.
+            fn with_port(object: std::sync::Arc<dyn #trait_ident>, port: &dyn #env_path::Port) -> Self {
+                Self {
+                    object,
+                    ids: [#port_ids],
+                    format: #port_format,
                 }
             }
         }
         impl #env_path::Dispatch for #struct_ident {
-            fn dispatch_and_call(&self, method: #env_path::MethodId, args: &[u8]) -> Vec<u8> {
+            fn dispatch_and_call(&self, method: #env_path::MethodId, args: &[u8], response: &mut #env_path::PacketBuilder) -> Result<(), #env_path::Error> {
                 #if_else_clauses
             }
+            fn method_name(&self, method: #env_path::MethodId) -> Option<(&'static str, &'static str)> {
+                const NAMES: [&str; #number_of_methods] = [#method_names];
+                let i = self.ids.iter().position(|id| *id == method)?;
+                Some((#lit_trait_name, NAMES[i]))
+            }
         }
         impl #env_path::ExportService<dyn #trait_ident> for dyn #trait_ident {
             fn export(port: std::sync::Weak<dyn #env_path::Port>, object: std::sync::Arc<dyn #trait_ident>) -> #env_path::HandleToExchange {
-                port.upgrade().unwrap().register(std::sync::Arc::new(#struct_ident::new(object)))
+                let port = port.upgrade().unwrap();
+                port.register(std::sync::Arc::new(#struct_ident::with_port(object, &*port)))
+            }
+        }
+    })
+}
+
+/// Generates `FooAsync` and its dispatcher for `#[service(async_dispatch)]`.
+/// `FooAsync` has the methods of `Foo`, but each takes the arguments by value and returns a future.
+/// The importer can't tell it from `Foo`, since it dispatches the same method ids.
+pub fn generate_async_dispatcher(
+    source_trait: &syn::ItemTrait,
+    args: &ServiceArgs,
+) -> Result<TokenStream2, TokenStream2> {
+    let env_path = create_env_path();
+    let trait_ident = source_trait.ident.clone();
+    let async_trait_ident = quote::format_ident!("{}Async", trait_ident);
+    let struct_ident = quote::format_ident!("{}AsyncDispatcher", trait_ident);
+    let lit_trait_name = syn::LitStr::new(&trait_ident.to_string(), Span::call_site());
+
+    let mut trait_methods = TokenStream2::new();
+    let mut if_else_clauses = TokenStream2::new();
+    let mut port_ids = TokenStream2::new();
+    let mut method_names = TokenStream2::new();
+
+    for (i, item) in source_trait.items.iter().enumerate() {
+        let method = match item {
+            syn::TraitItem::Method(x) => x,
+            non_method => {
+                return Err(
+                    syn::Error::new_spanned(non_method, "Service trait must have only methods").to_compile_error()
+                )
+            }
+        };
+        let method_ident = &method.sig.ident;
+        let lit_method_name = syn::LitStr::new(&method_ident.to_string(), Span::call_site());
+        port_ids.extend(quote! {port.local_method_id(#lit_trait_name, #lit_method_name),});
+        method_names.extend(quote! {#lit_method_name,});
+
+        let no_self = "All your method must take &self";
+        if let syn::FnArg::Typed(_) =
+            method.sig.inputs.first().ok_or_else(|| syn::Error::new_spanned(method, no_self).to_compile_error())?
+        {
+            return Err(syn::Error::new_spanned(method, no_self).to_compile_error())
+        }
+
+        let mut arg_idents = Vec::new();
+        let mut arg_types = Vec::new();
+        for (j, arg_source) in method.sig.inputs.iter().skip(1).enumerate() {
+            let arg_type = match arg_source {
+                syn::FnArg::Typed(pattern) => &*pattern.ty,
+                _ => unreachable!(),
+            };
+            arg_idents.push(quote::format_ident!("a{}", j + 1));
+            arg_types.push(
+                super::types::owned_type(arg_type)
+                    .map_err(|e| syn::Error::new_spanned(arg_source, &e).to_compile_error())?,
+            );
+        }
+        let return_type = match &method.sig.output {
+            syn::ReturnType::Type(_, t) => quote! {#t},
+            syn::ReturnType::Default => quote! {()},
+        };
+
+        trait_methods.extend(quote! {
+            fn #method_ident(self: std::sync::Arc<Self>, #(#arg_idents: #arg_types),*) -> #env_path::BoxFuture<#return_type>;
+        });
+        if_else_clauses.extend(quote! {
+            if method == self.ids[#i] {
+                let object = std::sync::Arc::clone(&self.object);
+                return #env_path::serve(self.format, request, move |(#(#arg_idents,)*): (#(#arg_types,)*)| {
+                    object.#method_ident(#(#arg_idents),*)
+                });
+            }
+        });
+    }
+    if_else_clauses.extend(quote! {
+        panic!("Invalid remote-trait-object call. Fatal Error.")
+    });
+
+    let number_of_methods = source_trait.items.len();
+    let port_format = args.format_or(quote! {port.format()});
+    Ok(quote! {
+        pub trait #async_trait_ident: Send + Sync {
+            #trait_methods
+        }
+        pub struct #struct_ident {
+            object: std::sync::Arc<dyn #async_trait_ident>,
+            ids: [#env_path::MethodId; #number_of_methods],
+            format: #env_path::Format,
+        }
+        impl #struct_ident {
+            /// Dispatches with the ids of the port.
+            fn with_port(object: std::sync::Arc<dyn #async_trait_ident>, port: &dyn #env_path::Port) -> Self {
+                Self {
+                    object,
+                    ids: [#port_ids],
+                    format: #port_format,
+                }
+            }
+        }
+        impl #env_path::AsyncDispatch for #struct_ident {
+            fn dispatch_and_call_async(&self, method: #env_path::MethodId, request: std::sync::Arc<#env_path::Packet>) -> #env_path::BoxFuture<Result<#env_path::PacketBuilder, #env_path::Error>> {
+                #if_else_clauses
+            }
+            fn method_name(&self, method: #env_path::MethodId) -> Option<(&'static str, &'static str)> {
+                const NAMES: [&str; #number_of_methods] = [#method_names];
+                let i = self.ids.iter().position(|id| *id == method)?;
+                Some((#lit_trait_name, NAMES[i]))
+            }
+        }
+        impl #env_path::ExportAsyncService<dyn #async_trait_ident> for dyn #async_trait_ident {
+            fn export_async(port: std::sync::Weak<dyn #env_path::Port>, object: std::sync::Arc<dyn #async_trait_ident>) -> #env_path::HandleToExchange {
+                let port = port.upgrade().unwrap();
+                port.register_async(std::sync::Arc::new(#struct_ident::with_port(object, &*port)))
             }
         }
     })
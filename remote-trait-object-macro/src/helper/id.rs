@@ -22,11 +22,53 @@ pub fn id_method_ident(the_trait: &syn::ItemTrait, method: &syn::TraitItemMethod
     quote::format_ident!("ID_METHOD_{}_{}", the_trait.ident, method.sig.ident)
 }
 
+/// How the default id of a method is decided.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum IdScheme {
+    /// By the order of declaration. Reordering the methods breaks the compatibility.
+    #[default]
+    Order,
+    /// By the hash of the module path, the trait name, the method name and the signature.
+    /// Moving the trait to another module changes the ids.
+    Hash,
+}
+
 fn lit_index(index: usize) -> syn::Lit {
     // We put a distinctive offset for the easy debug.
     syn::Lit::Int(syn::LitInt::new(&format!("{}", index + 70), Span::call_site()))
 }
 
+/// Signature without the names of the arguments, so that renaming an argument keeps the id.
+fn signature_string(sig: &syn::Signature) -> String {
+    let mut result = String::new();
+    for input in sig.inputs.iter() {
+        let tokens = match input {
+            syn::FnArg::Receiver(receiver) => quote! {#receiver},
+            syn::FnArg::Typed(pattern) => {
+                let ty = &pattern.ty;
+                quote! {#ty}
+            }
+        };
+        result.push_str(&tokens.to_string());
+        result.push(',');
+    }
+    let output = &sig.output;
+    result.push_str(&quote! {#output}.to_string());
+    // Spacing of the tokens depends on the compiler
+    result.retain(|c| !c.is_whitespace());
+    result
+}
+
+fn hash_key(the_trait: &syn::ItemTrait, method: &syn::TraitItemMethod) -> String {
+    format!("{}::{}({})", the_trait.ident, method.sig.ident, signature_string(&method.sig))
+}
+
+/// The module path is known only to the compiler, so the hash is computed in a const context.
+fn hash_id(env_path: &syn::Path, the_trait: &syn::ItemTrait, method: &syn::TraitItemMethod) -> TokenStream2 {
+    let lit_key = syn::LitStr::new(&hash_key(the_trait, method), Span::call_site());
+    quote! {#env_path::hash_method_id(module_path!(), #lit_key)}
+}
+
 fn id_method_entry_ident(the_trait: &syn::ItemTrait, method: &syn::TraitItemMethod) -> Ident {
     quote::format_ident!("ID_METHOD_ENTRY_{}_{}", the_trait.ident, method.sig.ident)
 }
@@ -35,7 +77,11 @@ fn id_method_setter_ident(the_trait: &syn::ItemTrait, method: &syn::TraitItemMet
     quote::format_ident!("id_method_setter_{}_{}", the_trait.ident, method.sig.ident)
 }
 
-pub fn generate_id(source_trait: &syn::ItemTrait) -> Result<TokenStream2, TokenStream2> {
+fn id_method_getter_ident(the_trait: &syn::ItemTrait, method: &syn::TraitItemMethod) -> Ident {
+    quote::format_ident!("id_method_getter_{}_{}", the_trait.ident, method.sig.ident)
+}
+
+pub fn generate_id(source_trait: &syn::ItemTrait, id_scheme: IdScheme) -> Result<TokenStream2, TokenStream2> {
     let env_path = create_env_path();
     let lit_trait_name = syn::LitStr::new(&format!("{}", source_trait.ident), Span::call_site());
     let mut method_id_table = TokenStream2::new();
@@ -49,25 +95,59 @@ pub fn generate_id(source_trait: &syn::ItemTrait) -> Result<TokenStream2, TokenS
                 )
             }
         };
-        let lit_index = lit_index(i);
+        let id = match id_scheme {
+            IdScheme::Order => {
+                let lit_index = lit_index(i);
+                quote! {#lit_index}
+            }
+            IdScheme::Hash => hash_id(&env_path, source_trait, method),
+        };
         let lit_method_name = syn::LitStr::new(&format!("{}", method.sig.ident), Span::call_site());
 
-        let id_ident = id_method_ident(&source_trait, method);
-        let id_entry_ident = id_method_entry_ident(&source_trait, method);
-        let id_setter_ident = id_method_setter_ident(&source_trait, method);
+        let id_ident = id_method_ident(source_trait, method);
+        let id_entry_ident = id_method_entry_ident(source_trait, method);
+        let id_setter_ident = id_method_setter_ident(source_trait, method);
+        let id_getter_ident = id_method_getter_ident(source_trait, method);
         let id_entry = quote! {
             #[allow(non_upper_case_globals)]
-            static #id_ident: #env_path::MethodIdAtomic = #env_path::MethodIdAtomic::new(#lit_index);
+            static #id_ident: #env_path::MethodIdAtomic = #env_path::MethodIdAtomic::new(#id);
             #[linkme::distributed_slice(#env_path::MID_REG)]
             #[allow(non_upper_case_globals)]
-            static #id_entry_ident: (&'static str, &'static str, fn(id: #env_path::MethodId)) =
-            (#lit_trait_name, #lit_method_name, #id_setter_ident);
+            static #id_entry_ident: (&'static str, &'static str, fn(id: #env_path::MethodId), fn() -> #env_path::MethodId) =
+            (#lit_trait_name, #lit_method_name, #id_setter_ident, #id_getter_ident);
             #[allow(non_snake_case)]
             fn #id_setter_ident(id: #env_path::MethodId) {
                 #id_ident.store(id, #env_path::ID_ORDERING);
             }
+            #[allow(non_snake_case)]
+            fn #id_getter_ident() -> #env_path::MethodId {
+                #id_ident.load(#env_path::ID_ORDERING)
+            }
         };
         method_id_table.extend(id_entry);
     }
     Ok(method_id_table)
 }
+
+#[test]
+fn stable_hash_key() {
+    let key_of = |source: &str| {
+        let the_trait = syn::parse_str::<syn::ItemTrait>(source).unwrap();
+        the_trait
+            .items
+            .iter()
+            .map(|item| match item {
+                syn::TraitItem::Method(method) => (method.sig.ident.to_string(), hash_key(&the_trait, method)),
+                _ => unreachable!(),
+            })
+            .collect::<std::collections::HashMap<_, _>>()
+    };
+    let keys = key_of("trait A { fn f(&self, a: i32) -> String; fn g(&self); }");
+    // Neither the order nor the argument names nor the spacing matter
+    assert_eq!(keys, key_of("trait A { fn g(&self); fn f(&self, b: i32)->String; }"));
+    assert_ne!(keys["f"], key_of("trait A { fn f(&self, a: i64) -> String; }")["f"]);
+    assert_ne!(keys["f"], key_of("trait B { fn f(&self, a: i32) -> String; }")["f"]);
+    // It must not change between the builds
+    assert_eq!(keys["f"], "A::f(&self,i32,->String)");
+    assert_eq!(keys["g"], "A::g(&self,)");
+}
@@ -14,18 +14,21 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use super::attribute::ServiceArgs;
 use super::path_of_single_ident;
 use crate::create_env_path;
 use proc_macro2::{Span, TokenStream as TokenStream2};
 use quote::ToTokens;
 
-pub fn generate_remote(source_trait: &syn::ItemTrait) -> Result<TokenStream2, TokenStream2> {
+pub fn generate_remote(source_trait: &syn::ItemTrait, args: &ServiceArgs) -> Result<TokenStream2, TokenStream2> {
     let env_path = create_env_path();
+    let with_format = with_format(args);
 
     let trait_ident = source_trait.ident.clone();
     let struct_ident = quote::format_ident!("{}Remote", trait_ident);
     let mut imported_struct = quote! {
-        #[derive(Debug)]
+        /// A clone adds a reference to the same service object, rather than exporting it again.
+        #[derive(Debug, Clone)]
         pub struct #struct_ident {
             handle: #env_path::Handle
         }
@@ -45,35 +48,31 @@ pub fn generate_remote(source_trait: &syn::ItemTrait) -> Result<TokenStream2, To
                 )
             }
         };
-        let id_ident = super::id::id_method_ident(source_trait, method);
+        let lit_trait_name = syn::LitStr::new(&trait_ident.to_string(), Span::call_site());
+        let lit_method_name = syn::LitStr::new(&method.sig.ident.to_string(), Span::call_site());
 
         let mut the_method = syn::parse_str::<syn::ImplItemMethod>("fn dummy() -> () {}").unwrap();
         the_method.sig = method.sig.clone();
-        let mut arguments_in_tuple = syn::ExprTuple {
-            attrs: Vec::new(),
-            paren_token: syn::token::Paren(Span::call_site()),
-            elems: syn::punctuated::Punctuated::new(),
+        let arguments_in_tuple = arguments_in_tuple(method)?;
+
+        // Failures of the call are returned only if the method is declared to return them.
+        // The exporter serializes the whole `Result`, so we flatten it with the transport error here.
+        let timeout = match super::attribute::timeout_ms(method)? {
+            Some(ms) => quote! {Some(std::time::Duration::from_millis(#ms))},
+            None => quote! {None},
         };
-        for arg in &method.sig.inputs {
-            match arg {
-                syn::FnArg::Receiver(_) => continue, // &self
-                syn::FnArg::Typed(pattern) => {
-                    if let syn::Pat::Ident(the_arg) = &*pattern.pat {
-                        arguments_in_tuple.elems.push(syn::Expr::Path(syn::ExprPath {
-                            attrs: Vec::new(),
-                            qself: None,
-                            path: path_of_single_ident(the_arg.ident.clone()),
-                        }));
-                    } else {
-                        return Err(syn::Error::new_spanned(arg, "You must not use a pattern for the argument")
-                            .to_compile_error())
-                    }
-                }
+        let the_call = if super::attribute::is_oneway(method)? {
+            quote! {
+                self.handle.call_oneway((#lit_trait_name, #lit_method_name), &#arguments_in_tuple)
+            }
+        } else if super::types::is_error_result(&method.sig.output) {
+            quote! {
+                self.handle.try_call((#lit_trait_name, #lit_method_name), &#arguments_in_tuple, #timeout).and_then(|result| result)
+            }
+        } else {
+            quote! {
+                self.handle.call((#lit_trait_name, #lit_method_name), &#arguments_in_tuple, #timeout)
             }
-        }
-
-        let the_call = quote! {
-            self.handle.call(#id_ident.load(#env_path::ID_ORDERING), &#arguments_in_tuple)
         };
         the_method.block.stmts.push(syn::Stmt::Expr(syn::Expr::Verbatim(the_call)));
         imported_struct_impl.items.push(syn::ImplItem::Method(the_method));
@@ -83,12 +82,121 @@ pub fn generate_remote(source_trait: &syn::ItemTrait) -> Result<TokenStream2, To
         impl #env_path::Service for #struct_ident {
         }
         impl #env_path::ImportService<dyn #trait_ident> for dyn #trait_ident {
-            fn import(port: std::sync::Weak<dyn #env_path::Port>, handle: #env_path::HandleToExchange) -> std::sync::Arc<dyn #trait_ident> {
+            fn import_with_timeout(port: std::sync::Weak<dyn #env_path::Port>, handle: #env_path::HandleToExchange, timeout: Option<std::time::Duration>) -> std::sync::Arc<dyn #trait_ident> {
                 std::sync::Arc::new(#struct_ident {
-                    handle: #env_path::Handle::careful_new(handle, port),
+                    handle: #env_path::Handle::careful_new(handle, port).with_timeout(timeout)#with_format,
                 })
             }
         }
     });
     Ok(imported_struct.to_token_stream())
 }
+
+/// Generates `FooAsyncRemote` for `#[service(async_remote)]`.
+/// It has the methods of the trait, but each returns a future of the result instead of blocking.
+/// Timeouts of the methods don't apply, since the caller can race the future with its own timer.
+pub fn generate_async_remote(source_trait: &syn::ItemTrait, args: &ServiceArgs) -> Result<TokenStream2, TokenStream2> {
+    let env_path = create_env_path();
+    let with_format = with_format(args);
+
+    let trait_ident = source_trait.ident.clone();
+    let struct_ident = quote::format_ident!("{}AsyncRemote", trait_ident);
+    let lit_trait_name = syn::LitStr::new(&trait_ident.to_string(), Span::call_site());
+    let mut methods = TokenStream2::new();
+
+    for item in source_trait.items.iter() {
+        let method = match item {
+            syn::TraitItem::Method(x) => x,
+            non_method => {
+                return Err(
+                    syn::Error::new_spanned(non_method, "Service trait must have only methods").to_compile_error()
+                )
+            }
+        };
+        let lit_method_name = syn::LitStr::new(&method.sig.ident.to_string(), Span::call_site());
+        let method_ident = &method.sig.ident;
+        let inputs = &method.sig.inputs;
+        let arguments_in_tuple = arguments_in_tuple(method)?;
+        let return_type = match &method.sig.output {
+            syn::ReturnType::Type(_, t) => quote! {#t},
+            syn::ReturnType::Default => quote! {()},
+        };
+
+        // Oneway method doesn't wait for anything, so it needs no future.
+        methods.extend(if super::attribute::is_oneway(method)? {
+            quote! {
+                pub fn #method_ident(#inputs) -> Result<(), #env_path::Error> {
+                    self.handle.try_call_oneway((#lit_trait_name, #lit_method_name), &#arguments_in_tuple)
+                }
+            }
+        } else if super::types::is_error_result(&method.sig.output) {
+            quote! {
+                pub fn #method_ident(#inputs) -> impl std::future::Future<Output = #return_type> + Send + 'static {
+                    let response = self.handle.call_async::<_, #return_type>((#lit_trait_name, #lit_method_name), &#arguments_in_tuple);
+                    async move { response.await.and_then(|result| result) }
+                }
+            }
+        } else {
+            quote! {
+                pub fn #method_ident(#inputs) -> impl std::future::Future<Output = Result<#return_type, #env_path::Error>> + Send + 'static {
+                    self.handle.call_async((#lit_trait_name, #lit_method_name), &#arguments_in_tuple)
+                }
+            }
+        });
+    }
+
+    Ok(quote! {
+        /// A clone adds a reference to the same service object, rather than exporting it again.
+        #[derive(Debug, Clone)]
+        pub struct #struct_ident {
+            handle: #env_path::Handle
+        }
+        impl #struct_ident {
+            pub fn import(port: std::sync::Weak<dyn #env_path::Port>, handle: #env_path::HandleToExchange) -> Self {
+                #struct_ident {
+                    handle: #env_path::Handle::careful_new(handle, port)#with_format,
+                }
+            }
+            #methods
+        }
+    })
+}
+
+/// Sets the format of the trait to the handle, if it has one.
+fn with_format(args: &ServiceArgs) -> TokenStream2 {
+    match &args.codec {
+        Some(_) => {
+            let format = args.format_or(TokenStream2::new());
+            quote! {.with_format(#format)}
+        }
+        None => TokenStream2::new(),
+    }
+}
+
+/// `(a, b, ...)` of the arguments of the method, which are sent to the exporter.
+fn arguments_in_tuple(method: &syn::TraitItemMethod) -> Result<syn::ExprTuple, TokenStream2> {
+    let mut arguments_in_tuple = syn::ExprTuple {
+        attrs: Vec::new(),
+        paren_token: syn::token::Paren(Span::call_site()),
+        elems: syn::punctuated::Punctuated::new(),
+    };
+    for arg in &method.sig.inputs {
+        match arg {
+            syn::FnArg::Receiver(_) => continue, // &self
+            syn::FnArg::Typed(pattern) => {
+                if let syn::Pat::Ident(the_arg) = &*pattern.pat {
+                    arguments_in_tuple.elems.push(syn::Expr::Path(syn::ExprPath {
+                        attrs: Vec::new(),
+                        qself: None,
+                        path: path_of_single_ident(the_arg.ident.clone()),
+                    }));
+                } else {
+                    return Err(
+                        syn::Error::new_spanned(arg, "You must not use a pattern for the argument").to_compile_error()
+                    )
+                }
+            }
+        }
+    }
+    Ok(arguments_in_tuple)
+}
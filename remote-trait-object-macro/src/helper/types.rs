@@ -52,6 +52,75 @@ pub fn is_ref(the_type: &syn::Type) -> Result<Option<syn::Type>, String> {
     }
 }
 
+/// The type that an async service method takes for the argument.
+/// Its future can't borrow the arguments, so it takes them by value.
+pub fn owned_type(the_type: &syn::Type) -> Result<syn::Type, String> {
+    match the_type {
+        syn::Type::Reference(x) => match &*x.elem {
+            syn::Type::Slice(slice) => {
+                let elem = &slice.elem;
+                Ok(syn::parse2::<syn::Type>(quote! {Vec<#elem>}).unwrap())
+            }
+            _ => Ok(is_ref(the_type)?.expect("It is a reference")),
+        },
+        _ => Ok(the_type.clone()),
+    }
+}
+
+/// Checks whether the return type is `Result<T, remote_trait_object::Error>`.
+/// For such methods, the failure of the remote call goes into the return value instead of a panic.
+pub fn is_error_result(output: &syn::ReturnType) -> bool {
+    let the_type = match output {
+        syn::ReturnType::Type(_, t) => &**t,
+        syn::ReturnType::Default => return false,
+    };
+    let path = match the_type {
+        syn::Type::Path(x) if x.qself.is_none() => &x.path,
+        _ => return false,
+    };
+    let last = match path.segments.last() {
+        Some(x) if x.ident == "Result" => x,
+        _ => return false,
+    };
+    let args = match &last.arguments {
+        syn::PathArguments::AngleBracketed(x) if x.args.len() == 2 => &x.args,
+        _ => return false,
+    };
+    match &args[1] {
+        syn::GenericArgument::Type(syn::Type::Path(error)) if error.qself.is_none() => {
+            let segments: Vec<String> = error.path.segments.iter().map(|s| s.ident.to_string()).collect();
+            segments.ends_with(&["remote_trait_object".to_owned(), "Error".to_owned()])
+        }
+        _ => false,
+    }
+}
+
+#[test]
+fn recognize_error_result() {
+    let t = syn::parse_str::<syn::ReturnType>("-> Result<u32, remote_trait_object::Error>").unwrap();
+    assert!(is_error_result(&t));
+    let t = syn::parse_str::<syn::ReturnType>("-> std::result::Result<(), ::remote_trait_object::Error>").unwrap();
+    assert!(is_error_result(&t));
+    let t = syn::parse_str::<syn::ReturnType>("-> Result<(), ()>").unwrap();
+    assert!(!is_error_result(&t));
+    let t = syn::parse_str::<syn::ReturnType>("-> Result<(), Error>").unwrap();
+    assert!(!is_error_result(&t));
+    let t = syn::parse_str::<syn::ReturnType>("").unwrap();
+    assert!(!is_error_result(&t));
+}
+
+#[test]
+fn convert_to_owned() {
+    let t = syn::parse_str::<syn::Type>("&[u8]").unwrap();
+    assert_eq!(owned_type(&t).unwrap(), syn::parse_str::<syn::Type>("Vec<u8>").unwrap());
+    let t = syn::parse_str::<syn::Type>("&str").unwrap();
+    assert_eq!(owned_type(&t).unwrap(), syn::parse_str::<syn::Type>("String").unwrap());
+    let t = syn::parse_str::<syn::Type>("u32").unwrap();
+    assert_eq!(owned_type(&t).unwrap(), t);
+    let t = syn::parse_str::<syn::Type>("&mut i32").unwrap();
+    assert!(owned_type(&t).is_err())
+}
+
 #[test]
 fn recognize_ref() {
     let t = syn::parse_str::<syn::Type>("Vec<u32>").unwrap();
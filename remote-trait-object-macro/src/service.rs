@@ -18,25 +18,36 @@ use crate::helper;
 use proc_macro2::TokenStream as TokenStream2;
 
 pub fn service(args: TokenStream2, input: TokenStream2) -> Result<TokenStream2, TokenStream2> {
-    if !args.is_empty() {
-        return Err(syn::Error::new_spanned(input, "#[service] doesn't take any argument").to_compile_error())
-    }
+    let args = helper::attribute::ServiceArgs::parse(args)?;
 
-    let source_trait = match syn::parse2::<syn::ItemTrait>(input.clone()) {
+    let mut source_trait = match syn::parse2::<syn::ItemTrait>(input.clone()) {
         Ok(x) => x,
         Err(_) => {
             return Err(syn::Error::new_spanned(input, "You can use #[service] only on a trait").to_compile_error())
         }
     };
 
-    let id = helper::id::generate_id(&source_trait)?;
-    let dispatcher = helper::dispatcher::generate_dispatcher(&source_trait)?;
-    let remote = helper::remote::generate_remote(&source_trait)?;
+    let id = helper::id::generate_id(&source_trait, args.id_scheme)?;
+    let dispatcher = helper::dispatcher::generate_dispatcher(&source_trait, &args)?;
+    let remote = helper::remote::generate_remote(&source_trait, &args)?;
+    let async_dispatcher = if args.async_dispatch {
+        helper::dispatcher::generate_async_dispatcher(&source_trait, &args)?
+    } else {
+        TokenStream2::new()
+    };
+    let async_remote = if args.async_remote {
+        helper::remote::generate_async_remote(&source_trait, &args)?
+    } else {
+        TokenStream2::new()
+    };
+    helper::attribute::strip_method_attributes(&mut source_trait);
 
     Ok(quote! {
         #source_trait
         #id
         #dispatcher
+        #async_dispatcher
         #remote
+        #async_remote
     })
 }
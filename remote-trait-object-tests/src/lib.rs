@@ -14,14 +14,30 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+#[cfg(test)]
 #[macro_use]
 extern crate log;
 extern crate remote_trait_object_macro as rto_macro;
 
+#[cfg(test)]
+mod test_async;
+#[cfg(test)]
+mod test_codec;
 #[cfg(test)]
 mod test_concurrent_ping;
+#[cfg(test)]
+mod test_disconnect;
+#[cfg(test)]
+mod test_interceptor;
+#[cfg(test)]
+mod test_oneway;
 //#[cfg(test)]
 //mod test_module;
-pub mod ipc;
+#[cfg(test)]
+mod test_remote_panic;
+#[cfg(test)]
+mod test_shutdown;
 #[cfg(test)]
 mod test_store;
+#[cfg(test)]
+mod test_timeout;
@@ -0,0 +1,373 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+use remote_trait_object::*;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+#[rto_macro::service(async_remote)]
+pub trait Calculator: Service {
+    fn add(&self, a: u32, b: u32) -> u32;
+    fn checked_sub(&self, a: u32, b: u32) -> Result<u32, remote_trait_object::Error>;
+    fn wait(&self) -> u32;
+    #[oneway]
+    fn notify(&self);
+}
+
+struct MyCalculator {
+    gate: Receiver<()>,
+}
+
+impl Service for MyCalculator {}
+
+impl Calculator for MyCalculator {
+    fn add(&self, a: u32, b: u32) -> u32 {
+        a + b
+    }
+
+    fn checked_sub(&self, a: u32, b: u32) -> Result<u32, remote_trait_object::Error> {
+        Ok(a.checked_sub(b).expect("Overflow"))
+    }
+
+    fn wait(&self) -> u32 {
+        self.gate.recv().unwrap();
+        7
+    }
+
+    fn notify(&self) {}
+}
+
+/// Unparks the thread of `block_on`, and remembers that it is woken.
+struct ThreadWaker {
+    thread: Thread,
+    woken: AtomicBool,
+}
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+fn thread_waker() -> Arc<ThreadWaker> {
+    Arc::new(ThreadWaker {
+        thread: thread::current(),
+        woken: AtomicBool::new(false),
+    })
+}
+
+fn poll_once<F: Future + Unpin>(future: &mut F, waker: &Arc<ThreadWaker>) -> Poll<F::Output> {
+    let waker = Waker::from(Arc::clone(waker));
+    Pin::new(future).poll(&mut TaskContext::from_waker(&waker))
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = thread_waker();
+    loop {
+        if let Poll::Ready(output) = poll_once(&mut future, &waker) {
+            return output
+        }
+        thread::park_timeout(Duration::from_secs(1));
+    }
+}
+
+fn setup(call_slots: usize) -> (Context, Context, CalculatorAsyncRemote, crossbeam::channel::Sender<()>) {
+    let (importer, exporter) = ContextBuilder::new().call_slots(call_slots).build_pair().unwrap();
+    let (gate_send, gate_recv) = bounded(2);
+    exporter.publish::<dyn Calculator>(
+        "calculator",
+        Arc::new(MyCalculator {
+            gate: gate_recv,
+        }),
+    );
+    let calculator = CalculatorAsyncRemote::import(importer.get_port(), importer.lookup_handle("calculator").unwrap());
+    (importer, exporter, calculator, gate_send)
+}
+
+#[test]
+fn async_call() {
+    let (importer, exporter, calculator, _gate_send) = setup(4);
+    assert_eq!(block_on(calculator.add(1, 2)), Ok(3));
+    assert_eq!(block_on(calculator.checked_sub(3, 2)), Ok(1));
+    assert!(matches!(block_on(calculator.checked_sub(2, 3)), Err(Error::RemotePanic { .. })));
+    assert_eq!(calculator.notify(), Ok(()));
+
+    drop(importer);
+    drop(exporter);
+}
+
+#[test]
+fn cloned_remote() {
+    let (importer, exporter, calculator, _gate_send) = setup(4);
+    let cloned = calculator.clone();
+    drop(calculator);
+    assert_eq!(block_on(cloned.add(1, 2)), Ok(3));
+
+    // The future keeps the object alive after the last remote is dropped.
+    let sum = cloned.add(2, 3);
+    drop(cloned);
+    assert_eq!(block_on(sum), Ok(5));
+
+    drop(importer);
+    drop(exporter);
+}
+
+#[test]
+fn waiting_for_slot() {
+    let (importer, exporter, calculator, gate_send) = setup(1);
+    let waker = thread_waker();
+
+    let mut wait = Box::pin(calculator.wait());
+    assert!(poll_once(&mut wait, &waker).is_pending());
+    // The only slot is taken by `wait`.
+    let mut sum = Box::pin(calculator.add(1, 2));
+    assert!(poll_once(&mut sum, &waker).is_pending());
+
+    gate_send.send(()).unwrap();
+    assert_eq!(block_on(wait), Ok(7));
+    assert!(waker.woken.load(Ordering::SeqCst));
+    assert_eq!(block_on(sum), Ok(3));
+
+    drop(calculator);
+    drop(importer);
+    drop(exporter);
+}
+
+#[test]
+fn wake_one_per_freed_slot() {
+    let (importer, exporter, calculator, gate_send) = setup(1);
+    let (first_waker, second_waker) = (thread_waker(), thread_waker());
+
+    let mut wait = Box::pin(calculator.wait());
+    assert!(poll_once(&mut wait, &thread_waker()).is_pending());
+    let mut first = Box::pin(calculator.add(1, 2));
+    assert!(poll_once(&mut first, &first_waker).is_pending());
+    let mut second = Box::pin(calculator.add(3, 4));
+    assert!(poll_once(&mut second, &second_waker).is_pending());
+
+    gate_send.send(()).unwrap();
+    assert_eq!(block_on(wait), Ok(7));
+    // Only the first in the queue is woken for the slot.
+    assert!(first_waker.woken.load(Ordering::SeqCst));
+    assert!(!second_waker.woken.load(Ordering::SeqCst));
+    // It passes the wake on, since it is dropped without taking the slot.
+    drop(first);
+    assert!(second_waker.woken.load(Ordering::SeqCst));
+    assert_eq!(block_on(second), Ok(7));
+
+    drop(calculator);
+    drop(importer);
+    drop(exporter);
+}
+
+#[test]
+fn dropped_future_releases_slot() {
+    let (importer, exporter, calculator, gate_send) = setup(1);
+    let waker = thread_waker();
+
+    let mut wait = Box::pin(calculator.wait());
+    assert!(poll_once(&mut wait, &waker).is_pending());
+    drop(wait);
+    // The late response to `wait` will be dropped.
+    assert_eq!(block_on(calculator.add(1, 2)), Ok(3));
+    gate_send.send(()).unwrap();
+    assert_eq!(block_on(calculator.add(3, 4)), Ok(7));
+
+    drop(calculator);
+    drop(importer);
+    drop(exporter);
+}
+
+#[rto_macro::service(async_remote, async_dispatch)]
+pub trait Gate: Service {
+    fn wait(&self, n: u32) -> u32;
+    fn open(&self);
+    fn fail(&self) -> Result<(), remote_trait_object::Error>;
+}
+
+#[derive(Default)]
+struct MyGate {
+    /// (opened, waiters)
+    state: Mutex<(bool, Vec<Waker>)>,
+}
+
+struct Opened(Arc<MyGate>);
+
+impl Future for Opened {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<()> {
+        let mut state = self.0.state.lock().unwrap();
+        if state.0 {
+            return Poll::Ready(())
+        }
+        state.1.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl GateAsync for MyGate {
+    fn wait(self: Arc<Self>, n: u32) -> BoxFuture<u32> {
+        Box::pin(async move {
+            Opened(self).await;
+            n
+        })
+    }
+
+    fn open(self: Arc<Self>) -> BoxFuture<()> {
+        Box::pin(async move {
+            let mut state = self.state.lock().unwrap();
+            state.0 = true;
+            for waker in state.1.drain(..) {
+                waker.wake();
+            }
+        })
+    }
+
+    fn fail(self: Arc<Self>) -> BoxFuture<Result<(), remote_trait_object::Error>> {
+        Box::pin(async move { panic!("Failed") })
+    }
+}
+
+struct Task {
+    future: Mutex<Option<BoxFuture<()>>>,
+    queue: Sender<Arc<Task>>,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        let queue = self.queue.clone();
+        // The executor thread is gone only after the test finished.
+        let _ = queue.send(self);
+    }
+}
+
+/// Polls all the tasks in a single thread.
+struct SingleThreadExecutor {
+    queue: Sender<Arc<Task>>,
+}
+
+impl SingleThreadExecutor {
+    fn start() -> Arc<Self> {
+        let (queue, tasks) = unbounded::<Arc<Task>>();
+        thread::spawn(move || {
+            while let Ok(task) = tasks.recv() {
+                let waker = Waker::from(Arc::clone(&task));
+                let mut future = task.future.lock().unwrap();
+                if let Some(mut pending) = future.take() {
+                    if pending.as_mut().poll(&mut TaskContext::from_waker(&waker)).is_pending() {
+                        *future = Some(pending);
+                    }
+                }
+            }
+        });
+        Arc::new(Self {
+            queue,
+        })
+    }
+}
+
+impl Executor for SingleThreadExecutor {
+    fn spawn(&self, task: BoxFuture<()>) {
+        self.queue
+            .send(Arc::new(Task {
+                future: Mutex::new(Some(task)),
+                queue: self.queue.clone(),
+            }))
+            .unwrap();
+    }
+}
+
+#[test]
+fn many_calls_on_executor() {
+    let n = 1000;
+    // A single server thread would be taken by the first wait, if it were not for the executor.
+    let (importer, exporter) = ContextBuilder::new()
+        .call_slots(n + 1)
+        .server_threads(1)
+        .executor(SingleThreadExecutor::start() as Arc<dyn Executor>)
+        .build_pair()
+        .unwrap();
+    exporter.publish_async::<dyn GateAsync>("gate", Arc::new(MyGate::default()));
+    let gate_async = GateAsyncRemote::import(importer.get_port(), importer.lookup_handle("gate").unwrap());
+    let gate = importer.lookup::<dyn Gate>("gate").unwrap();
+
+    let waker = thread_waker();
+    let mut waits: Vec<_> = (0..n as u32).map(|i| Box::pin(gate_async.wait(i))).collect();
+    for wait in waits.iter_mut() {
+        assert!(poll_once(wait, &waker).is_pending());
+    }
+    gate.open();
+    for (i, wait) in waits.into_iter().enumerate() {
+        assert_eq!(block_on(wait), Ok(i as u32));
+    }
+    assert!(matches!(gate.fail(), Err(Error::RemotePanic { .. })));
+
+    drop(gate);
+    drop(gate_async);
+    drop(importer);
+    drop(exporter);
+}
+
+/// The id of the service object, to make a request by hand.
+fn object_id(handle: HandleToExchange) -> u64 {
+    serde_cbor::from_slice(&serde_cbor::to_vec(&handle).unwrap()).unwrap()
+}
+
+#[test]
+fn invalid_arguments() {
+    let executor = SingleThreadExecutor::start() as Arc<dyn Executor>;
+    // Both on a server thread and on the executor
+    for builder in [ContextBuilder::new(), ContextBuilder::new().executor(executor)] {
+        let (importer, exporter) = builder.build_pair().unwrap();
+        exporter.publish_async::<dyn GateAsync>("gate", Arc::new(MyGate::default()));
+        let handle = importer.lookup_handle("gate").unwrap();
+
+        // The argument is not in CBOR.
+        let port = importer.get_port().upgrade().unwrap();
+        let wait = port.method_id("Gate", "wait").unwrap();
+        let result = port.call(Packet::new_request(object_id(handle), wait, &[0xff]), None);
+        assert!(matches!(result, Err(Error::DeserializationFailed(_))), "{:?}", result);
+        drop(port);
+
+        drop(importer);
+        drop(exporter);
+    }
+}
+
+#[test]
+fn async_dispatch_without_executor() {
+    let (importer, exporter) = Context::pair().unwrap();
+    exporter.publish_async::<dyn GateAsync>("gate", Arc::new(MyGate::default()));
+    let gate = importer.lookup::<dyn Gate>("gate").unwrap();
+
+    // The server thread waits for the future.
+    gate.open();
+    assert_eq!(gate.wait(3), 3);
+    assert!(matches!(gate.fail(), Err(Error::RemotePanic { .. })));
+
+    drop(gate);
+    drop(importer);
+    drop(exporter);
+}
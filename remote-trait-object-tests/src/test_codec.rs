@@ -0,0 +1,152 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use remote_trait_object::*;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Takes the format of the context that exports it
+#[rto_macro::service]
+pub trait Account: Service {
+    fn owner(&self) -> String;
+    fn withdraw(&self, amount: u32) -> Result<u32, ()>;
+}
+
+struct MyAccount {
+    owner: String,
+    balance: AtomicU32,
+}
+
+impl Service for MyAccount {}
+
+impl Account for MyAccount {
+    fn owner(&self) -> String {
+        self.owner.clone()
+    }
+
+    fn withdraw(&self, amount: u32) -> Result<u32, ()> {
+        let balance = self.balance.load(Ordering::SeqCst);
+        if balance < amount {
+            return Err(())
+        }
+        self.balance.store(balance - amount, Ordering::SeqCst);
+        Ok(balance - amount)
+    }
+}
+
+fn new_account(owner: &str, balance: u32) -> Arc<dyn Account> {
+    Arc::new(MyAccount {
+        owner: owner.to_owned(),
+        balance: AtomicU32::new(balance),
+    })
+}
+
+#[rto_macro::service(codec = "cbor")]
+pub trait CborBank: Service {
+    fn open(&self, owner: &str, deposit: u32) -> SArc<dyn Account>;
+    fn pay(&self, account: SArc<dyn Account>, amount: u32) -> Result<u32, ()>;
+}
+
+#[rto_macro::service(codec = "bincode")]
+pub trait BincodeBank: Service {
+    fn open(&self, owner: &str, deposit: u32) -> SArc<dyn Account>;
+    fn pay(&self, account: SArc<dyn Account>, amount: u32) -> Result<u32, ()>;
+}
+
+#[rto_macro::service(codec = "json")]
+pub trait JsonBank: Service {
+    fn open(&self, owner: &str, deposit: u32) -> SArc<dyn Account>;
+    fn pay(&self, account: SArc<dyn Account>, amount: u32) -> Result<u32, ()>;
+}
+
+struct MyBank;
+
+impl Service for MyBank {}
+
+impl CborBank for MyBank {
+    fn open(&self, owner: &str, deposit: u32) -> SArc<dyn Account> {
+        SArc::new(new_account(owner, deposit))
+    }
+
+    fn pay(&self, account: SArc<dyn Account>, amount: u32) -> Result<u32, ()> {
+        account.unwrap().withdraw(amount)
+    }
+}
+
+impl BincodeBank for MyBank {
+    fn open(&self, owner: &str, deposit: u32) -> SArc<dyn Account> {
+        CborBank::open(self, owner, deposit)
+    }
+
+    fn pay(&self, account: SArc<dyn Account>, amount: u32) -> Result<u32, ()> {
+        CborBank::pay(self, account, amount)
+    }
+}
+
+impl JsonBank for MyBank {
+    fn open(&self, owner: &str, deposit: u32) -> SArc<dyn Account> {
+        CborBank::open(self, owner, deposit)
+    }
+
+    fn pay(&self, account: SArc<dyn Account>, amount: u32) -> Result<u32, ()> {
+        CborBank::pay(self, account, amount)
+    }
+}
+
+/// Opens an account in the bank of the counterparty, and pays from both the remote account and a local one.
+fn check_bank(open: impl Fn(&str, u32) -> SArc<dyn Account>, pay: impl Fn(SArc<dyn Account>, u32) -> Result<u32, ()>) {
+    let remote_account = open("Alice", 10).unwrap();
+    assert_eq!(remote_account.owner(), "Alice");
+    assert_eq!(pay(SArc::new(Arc::clone(&remote_account)), 3), Ok(7));
+    assert_eq!(remote_account.withdraw(8), Err(()));
+
+    let local_account = new_account("Bob", 5);
+    assert_eq!(pay(SArc::new(Arc::clone(&local_account)), 6), Err(()));
+    assert_eq!(pay(SArc::new(local_account), 5), Ok(0));
+}
+
+fn check_every_bank(importer: &Context, exporter: &Context) {
+    exporter.publish::<dyn CborBank>("cbor", Arc::new(MyBank));
+    exporter.publish::<dyn BincodeBank>("bincode", Arc::new(MyBank));
+    exporter.publish::<dyn JsonBank>("json", Arc::new(MyBank));
+
+    let bank = importer.lookup::<dyn CborBank>("cbor").unwrap();
+    check_bank(|owner, deposit| bank.open(owner, deposit), |account, amount| bank.pay(account, amount));
+    let bank = importer.lookup::<dyn BincodeBank>("bincode").unwrap();
+    check_bank(|owner, deposit| bank.open(owner, deposit), |account, amount| bank.pay(account, amount));
+    let bank = importer.lookup::<dyn JsonBank>("json").unwrap();
+    check_bank(|owner, deposit| bank.open(owner, deposit), |account, amount| bank.pay(account, amount));
+}
+
+#[test]
+fn codec_of_trait() {
+    let (importer, exporter) = Context::pair().unwrap();
+    check_every_bank(&importer, &exporter);
+    drop(importer);
+    drop(exporter);
+}
+
+#[test]
+fn format_of_context() {
+    // `Account` is called in JSON to the exporter, and in bincode to the importer.
+    let (importer, exporter) = ContextBuilder::new()
+        .format(Format::Bincode)
+        .build_pair_with(ContextBuilder::new().format(Format::Json))
+        .unwrap();
+    check_every_bank(&importer, &exporter);
+    drop(importer);
+    drop(exporter);
+}
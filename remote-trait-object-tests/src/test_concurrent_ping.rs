@@ -14,9 +14,8 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::ipc::{IntraRecv, IntraSend, IpcEnds};
-use remote_trait_object::Context;
 use remote_trait_object::Packet;
+use remote_trait_object::{Context, ContextBuilder};
 use std::sync::mpsc;
 use std::sync::{Arc, Barrier};
 use std::thread;
@@ -33,27 +32,31 @@ fn init_logger() {
 // 2. Port's server calls 4 handlers in parallel.
 #[test]
 fn ping() {
+    ping_concurrently(4, ContextBuilder::new());
+}
+
+// The server handles as many calls in parallel as the number of its threads.
+#[test]
+fn ping_with_more_server_threads() {
+    ping_concurrently(16, ContextBuilder::new().server_threads(16).call_slots(16));
+}
+
+fn ping_concurrently(number_of_calls: usize, ping_module_builder: ContextBuilder) {
     init_logger();
 
-    panic_after(std::time::Duration::from_secs(1), || {
+    panic_after(std::time::Duration::from_secs(1), move || {
         debug!("ping test start");
-        let IpcEnds {
-            send1,
-            recv1,
-            send2,
-            recv2,
-        } = crate::ipc::create();
-
-        let number_of_calls = 4;
         let wait_before_test_end = 1;
 
         // We use barrier to check concurrency
         // This test blocks if the packets are not handled concurrently.
         let barrier = Arc::new(Barrier::new(number_of_calls + wait_before_test_end));
 
-        let _ping_module = create_ping_module(send2, recv2, Arc::clone(&barrier));
-
-        let cmd_to_ping_rto = Context::new(send1, recv1);
+        let (cmd_to_ping_rto, _ping_module) = connect_to_ping_module(
+            ContextBuilder::new().call_slots(number_of_calls),
+            ping_module_builder,
+            Arc::clone(&barrier),
+        );
         let mut handles = Vec::new();
 
         for i in 0..number_of_calls {
@@ -64,7 +67,7 @@ fn ping() {
                 .spawn(move || {
                     // FIXME: 0 is temporary value assuming singleton service object
                     let request = Packet::new_request(0, 1, &[]);
-                    let response = port.call(request.view());
+                    let response = port.call(request, None).unwrap();
                     assert_eq!(response.data(), b"pong");
                 })
                 .unwrap();
@@ -79,16 +82,56 @@ fn ping() {
     });
 }
 
-fn create_ping_module(ipc_send: IntraSend, ipc_recv: IntraRecv, barrier: Arc<Barrier>) -> Context {
-    let cmd_rto = Context::new(ipc_send, ipc_recv);
-    let port = cmd_rto.get_port().upgrade().unwrap();
+// Calls more than the number of call slots wait for a slot, rather than fail.
+#[test]
+fn ping_with_single_call_slot() {
+    init_logger();
+
+    panic_after(std::time::Duration::from_secs(1), || {
+        let (cmd_to_ping_rto, _ping_module) = connect_to_ping_module(
+            ContextBuilder::new().call_slots(1),
+            ContextBuilder::new(),
+            Arc::new(Barrier::new(1)),
+        );
+        let mut handles = Vec::new();
+
+        for i in 0..4 {
+            let port = cmd_to_ping_rto.get_port().upgrade().unwrap();
+
+            let joiner = thread::Builder::new()
+                .name(format!("ping sender {}", i))
+                .spawn(move || {
+                    for _ in 0..10 {
+                        let request = Packet::new_request(0, 1, &[]);
+                        let response = port.call(request, None).unwrap();
+                        assert_eq!(response.data(), b"pong");
+                    }
+                })
+                .unwrap();
+            handles.push(joiner);
+        }
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    });
+}
+
+/// Returns the context of the command and the context of the ping module, which are connected to each other.
+fn connect_to_ping_module(
+    cmd_builder: ContextBuilder,
+    ping_module_builder: ContextBuilder,
+    barrier: Arc<Barrier>,
+) -> (Context, Context) {
+    let (cmd_rto, ping_rto) = cmd_builder.build_pair_with(ping_module_builder).unwrap();
+    let port = ping_rto.get_port().upgrade().unwrap();
     let _handle_to_export = port.register(Arc::new(move |_method: u32, _args: &[u8]| {
         // Wait until barrier.wait is called in concurrently
         barrier.wait();
         b"pong".to_vec()
     }));
 
-    cmd_rto
+    (cmd_rto, ping_rto)
 }
 
 /// Copied from https://github.com/rust-lang/rfcs/issues/2798#issuecomment-552949300
@@ -0,0 +1,182 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+use remote_trait_object::ipc::{intra, IpcRecv, IpcSend};
+use remote_trait_object::*;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[rto_macro::service]
+pub trait Counter: Service {
+    fn count(&self) -> u32;
+}
+
+struct MyCounter;
+
+impl Service for MyCounter {}
+
+impl Counter for MyCounter {
+    fn count(&self) -> u32 {
+        1
+    }
+}
+
+#[rto_macro::service]
+pub trait Waiter: Service {
+    fn wait(&self) -> Result<(), remote_trait_object::Error>;
+}
+
+/// Tells when a call has come in, and holds it until the gate is opened.
+struct MyWaiter {
+    entered: Sender<()>,
+    gate: Receiver<()>,
+}
+
+impl Service for MyWaiter {}
+
+impl Waiter for MyWaiter {
+    fn wait(&self) -> Result<(), Error> {
+        self.entered.send(()).unwrap();
+        self.gate.recv().unwrap();
+        Ok(())
+    }
+}
+
+fn notified_on_disconnect() -> (ContextBuilder, Receiver<()>) {
+    let (disconnected_send, disconnected_recv) = bounded(1);
+    let builder = ContextBuilder::new().on_disconnect(move || disconnected_send.send(()).unwrap());
+    (builder, disconnected_recv)
+}
+
+#[test]
+fn release_on_disconnect() {
+    let (builder, disconnected) = notified_on_disconnect();
+    let (exporter, importer) = builder.build_pair_with(ContextBuilder::new()).unwrap();
+
+    let object = Arc::new(MyCounter);
+    exporter.publish::<dyn Counter>("counter", Arc::clone(&object) as Arc<dyn Counter>);
+    let counter = importer.lookup::<dyn Counter>("counter").unwrap();
+    assert_eq!(counter.count(), 1);
+    exporter.unpublish("counter");
+    assert_eq!(Arc::strong_count(&object), 2);
+
+    // The importer is gone without deleting the object.
+    drop(importer);
+    disconnected.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(Arc::strong_count(&object), 1);
+    assert_eq!(exporter.lookup_handle("counter"), Err(Error::ConnectionLost));
+
+    drop(counter);
+    drop(exporter);
+}
+
+#[test]
+fn fail_call_in_handler_of_dropped_peer() {
+    let (entered_send, entered_recv) = bounded(1);
+    let (gate_send, gate_recv) = bounded(1);
+    let (caller, peer) =
+        ContextBuilder::new().server_shutdown_timeout(Duration::from_millis(100)).build_pair().unwrap();
+    peer.publish::<dyn Waiter>(
+        "waiter",
+        Arc::new(MyWaiter {
+            entered: entered_send,
+            gate: gate_recv,
+        }),
+    );
+    let waiter = caller.lookup::<dyn Waiter>("waiter").unwrap();
+
+    thread::scope(|scope| {
+        let pending = scope.spawn(|| waiter.wait());
+        entered_recv.recv().unwrap();
+        // The peer goes away while the call is blocked in its handler.
+        drop(peer);
+        assert_eq!(pending.join().unwrap(), Err(Error::ConnectionLost));
+    });
+    gate_send.send(()).unwrap();
+    drop(waiter);
+    drop(caller);
+}
+
+#[test]
+fn local_drop_is_not_disconnect() {
+    let (builder1, disconnected1) = notified_on_disconnect();
+    let (builder2, disconnected2) = notified_on_disconnect();
+    let (context1, context2) = builder1.build_pair_with(builder2).unwrap();
+
+    drop(context1);
+    disconnected2.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(disconnected1.try_recv().is_err());
+    drop(context2);
+}
+
+#[test]
+fn heartbeat_keeps_idle_peer() {
+    let (context1, context2) = ContextBuilder::new().heartbeat(Duration::from_millis(100), 5).build_pair().unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(context1.health(), Health::Alive);
+    assert_eq!(context2.health(), Health::Alive);
+    drop(context1);
+    drop(context2);
+}
+
+#[test]
+fn heartbeat_detects_hung_peer() {
+    // The other end only echoes the handshake, and is never read after that, like a process that hangs.
+    let ((send1, recv1), (hung_send, hung_recv)) = intra::pair();
+    let echo = thread::spawn(move || {
+        hung_send.send(&hung_recv.recv(None).unwrap());
+        (hung_send, hung_recv)
+    });
+    let (lost_send, lost_recv) = bounded(1);
+    let (builder, disconnected) = notified_on_disconnect();
+    let context = builder
+        .heartbeat(Duration::from_millis(20), 3)
+        .on_peer_lost(move || lost_send.send(()).unwrap())
+        .build(send1, recv1)
+        .unwrap();
+    let _hung = echo.join().unwrap();
+    assert_eq!(context.health(), Health::Alive);
+
+    thread::scope(|scope| {
+        let pending = scope.spawn(|| context.lookup_handle("counter"));
+        lost_recv.recv_timeout(Duration::from_secs(1)).unwrap();
+        disconnected.try_recv().unwrap();
+        assert_eq!(context.health(), Health::Lost);
+        // The calls waiting for the hung counterparty fail.
+        assert_eq!(pending.join().unwrap(), Err(Error::ConnectionLost));
+    });
+    drop(context);
+}
+
+#[test]
+fn closed_is_not_lost() {
+    let (lost_send, lost_recv) = bounded(1);
+    let (context1, context2) = ContextBuilder::new()
+        .heartbeat(Duration::from_millis(100), 5)
+        .on_peer_lost(move || lost_send.send(()).unwrap())
+        .build_pair_with(ContextBuilder::new())
+        .unwrap();
+
+    drop(context2);
+    while context1.health() == Health::Alive {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(context1.health(), Health::Disconnected);
+    assert!(lost_recv.try_recv().is_err());
+    drop(context1);
+}
@@ -0,0 +1,299 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use remote_trait_object::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context as TaskContext, Wake, Waker};
+use std::thread::{self, Thread};
+
+#[rto_macro::service]
+pub trait Greeter: Service {
+    fn greet(&self, name: String) -> Result<String, remote_trait_object::Error>;
+}
+
+#[rto_macro::service]
+pub trait Counter: Service {
+    fn count(&self) -> u32;
+}
+
+struct MyGreeter;
+
+impl Service for MyGreeter {}
+
+impl Greeter for MyGreeter {
+    fn greet(&self, name: String) -> Result<String, Error> {
+        Ok(format!("Hello, {}", name))
+    }
+}
+
+struct MyCounter;
+
+impl Service for MyCounter {}
+
+impl Counter for MyCounter {
+    fn count(&self) -> u32 {
+        1
+    }
+}
+
+/// An async greeter that records whether a call is dispatched to it, before the future of the call is polled.
+#[derive(Default)]
+struct AsyncGreeter {
+    dispatched: AtomicBool,
+}
+
+impl AsyncDispatch for AsyncGreeter {
+    fn dispatch_and_call_async(
+        &self,
+        _method: MethodId,
+        _request: Arc<Packet>,
+    ) -> BoxFuture<Result<PacketBuilder, Error>> {
+        self.dispatched.store(true, Ordering::SeqCst);
+        Box::pin(async {
+            let mut response = PacketBuilder::new();
+            Format::Cbor.encode_into(&Ok::<_, Error>("Hello"), &mut response)?;
+            Ok(response)
+        })
+    }
+
+    fn method_name(&self, _method: MethodId) -> Option<(&'static str, &'static str)> {
+        Some(("Greeter", "greet"))
+    }
+}
+
+impl ExportAsyncService<AsyncGreeter> for AsyncGreeter {
+    fn export_async(port: Weak<dyn Port>, object: Arc<Self>) -> HandleToExchange {
+        port.upgrade().expect("Port is alive while publishing").register_async(object)
+    }
+}
+
+/// Runs each task on a thread of its own.
+struct ThreadExecutor;
+
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+impl Executor for ThreadExecutor {
+    fn spawn(&self, mut task: BoxFuture<()>) {
+        thread::spawn(move || {
+            let waker = Waker::from(Arc::new(Unpark(thread::current())));
+            while task.as_mut().poll(&mut TaskContext::from_waker(&waker)).is_pending() {
+                thread::park();
+            }
+        });
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Before(Call, Vec<u8>),
+    After(Call, Result<Vec<u8>, Error>),
+}
+
+#[derive(Default)]
+struct Recorder {
+    events: Mutex<Vec<Event>>,
+}
+
+impl Recorder {
+    fn take(&self) -> Vec<Event> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+impl Interceptor for Recorder {
+    fn before(&self, call: &Call, args: &[u8]) -> Result<(), Error> {
+        self.events.lock().unwrap().push(Event::Before(*call, args.to_vec()));
+        Ok(())
+    }
+
+    fn after(&self, call: &Call, result: Result<&[u8], &Error>) {
+        self.events.lock().unwrap().push(Event::After(*call, result.map(<[u8]>::to_vec).map_err(Clone::clone)));
+    }
+}
+
+/// Rejects every call to `Greeter::greet`.
+struct NoGreeting;
+
+impl Interceptor for NoGreeting {
+    fn before(&self, call: &Call, _args: &[u8]) -> Result<(), Error> {
+        if call.name == Some(("Greeter", "greet")) {
+            return Err(Error::Rejected("No greeting".to_owned()))
+        }
+        Ok(())
+    }
+}
+
+struct PanicOnGreeting;
+
+impl Interceptor for PanicOnGreeting {
+    fn before(&self, call: &Call, _args: &[u8]) -> Result<(), Error> {
+        if call.name == Some(("Greeter", "greet")) {
+            panic!("No greeting")
+        }
+        Ok(())
+    }
+}
+
+/// The importer and the exporter of a greeter and a counter.
+fn contexts(importer: ContextBuilder, exporter: ContextBuilder) -> (Context, Context) {
+    let (importer, exporter) = importer.build_pair_with(exporter).unwrap();
+    exporter.publish::<dyn Greeter>("greeter", Arc::new(MyGreeter));
+    exporter.publish::<dyn Counter>("counter", Arc::new(MyCounter));
+    (importer, exporter)
+}
+
+fn encode<T: serde::Serialize>(value: &T) -> Vec<u8> {
+    Format::Cbor.encode(value).unwrap()
+}
+
+#[test]
+fn both_sides() {
+    let client = Arc::new(Recorder::default());
+    let server = Arc::new(Recorder::default());
+    let (importer, exporter) = contexts(
+        ContextBuilder::new().interceptor(Arc::clone(&client) as Arc<dyn Interceptor>),
+        ContextBuilder::new().interceptor(Arc::clone(&server) as Arc<dyn Interceptor>),
+    );
+    let greeter = importer.lookup::<dyn Greeter>("greeter").unwrap();
+    let counter = importer.lookup::<dyn Counter>("counter").unwrap();
+    assert_eq!(greeter.greet("rto".to_owned()), Ok("Hello, rto".to_owned()));
+    assert_eq!(counter.count(), 1);
+
+    for (side, recorder) in [(Side::Client, &client), (Side::Server, &server)] {
+        let events = recorder.take();
+        assert_eq!(events.len(), 4, "{:?}", events);
+        let (greet, count) = match (&events[0], &events[2]) {
+            (Event::Before(greet, _), Event::Before(count, _)) => (*greet, *count),
+            _ => panic!("Unexpected events {:?}", events),
+        };
+        assert_eq!(greet.side, side);
+        assert_eq!(count.side, side);
+        // Both methods have the same id, since they are the first ones of their traits.
+        assert_eq!(greet.method, count.method);
+        assert_eq!(greet.name, Some(("Greeter", "greet")));
+        assert_eq!(count.name, Some(("Counter", "count")));
+        assert_ne!(greet.object_id, count.object_id);
+        assert_eq!(events, vec![
+            Event::Before(greet, encode(&("rto",))),
+            Event::After(greet, Ok(encode(&Ok::<_, Error>("Hello, rto")))),
+            Event::Before(count, encode(&())),
+            Event::After(count, Ok(encode(&1))),
+        ]);
+    }
+    drop(greeter);
+    drop(counter);
+    drop(importer);
+    drop(exporter);
+}
+
+#[test]
+fn reject_on_server() {
+    let server = Arc::new(Recorder::default());
+    let (importer, exporter) = contexts(
+        ContextBuilder::new(),
+        ContextBuilder::new()
+            .interceptor(Arc::clone(&server) as Arc<dyn Interceptor>)
+            .interceptor(Arc::new(NoGreeting)),
+    );
+    let greeter = importer.lookup::<dyn Greeter>("greeter").unwrap();
+    let counter = importer.lookup::<dyn Counter>("counter").unwrap();
+    let rejected = Error::Rejected("No greeting".to_owned());
+    assert_eq!(greeter.greet("rto".to_owned()), Err(rejected.clone()));
+    assert_eq!(counter.count(), 1);
+
+    // The interceptor before the rejecting one sees the rejection.
+    let events = server.take();
+    assert_eq!(events.len(), 4, "{:?}", events);
+    match &events[1] {
+        Event::After(call, result) => {
+            assert_eq!(call.name, Some(("Greeter", "greet")));
+            assert_eq!(result, &Err(rejected));
+        }
+        event => panic!("Unexpected event {:?}", event),
+    }
+
+    drop(greeter);
+    drop(counter);
+    drop(importer);
+    drop(exporter);
+}
+
+#[test]
+fn reject_on_client() {
+    let server = Arc::new(Recorder::default());
+    let (importer, exporter) = contexts(
+        ContextBuilder::new().interceptor(Arc::new(NoGreeting)),
+        ContextBuilder::new().interceptor(Arc::clone(&server) as Arc<dyn Interceptor>),
+    );
+    let greeter = importer.lookup::<dyn Greeter>("greeter").unwrap();
+    assert_eq!(greeter.greet("rto".to_owned()), Err(Error::Rejected("No greeting".to_owned())));
+    // The call is not sent.
+    assert_eq!(server.take(), Vec::new());
+
+    drop(greeter);
+    drop(importer);
+    drop(exporter);
+}
+
+#[test]
+fn panic_on_server() {
+    let (importer, exporter) =
+        contexts(ContextBuilder::new(), ContextBuilder::new().server_threads(1).interceptor(Arc::new(PanicOnGreeting)));
+    let greeter = importer.lookup::<dyn Greeter>("greeter").unwrap();
+    let counter = importer.lookup::<dyn Counter>("counter").unwrap();
+    match greeter.greet("rto".to_owned()) {
+        Err(Error::RemotePanic {
+            message,
+            ..
+        }) => assert_eq!(message, "No greeting"),
+        result => panic!("Unexpected result {:?}", result),
+    }
+    // The only server thread is still alive.
+    assert_eq!(counter.count(), 1);
+
+    drop(greeter);
+    drop(counter);
+    drop(importer);
+    drop(exporter);
+}
+
+#[test]
+fn reject_async_on_server() {
+    let greeter = Arc::new(AsyncGreeter::default());
+    let (importer, exporter) = ContextBuilder::new()
+        .build_pair_with(
+            ContextBuilder::new()
+                .executor(Arc::new(ThreadExecutor) as Arc<dyn Executor>)
+                .interceptor(Arc::new(NoGreeting)),
+        )
+        .unwrap();
+    exporter.publish_async("greeter", Arc::clone(&greeter));
+    let remote = importer.lookup::<dyn Greeter>("greeter").unwrap();
+    assert_eq!(remote.greet("rto".to_owned()), Err(Error::Rejected("No greeting".to_owned())));
+    // The service object doesn't see the rejected call at all.
+    assert!(!greeter.dispatched.load(Ordering::SeqCst));
+
+    drop(remote);
+    drop(importer);
+    drop(exporter);
+}
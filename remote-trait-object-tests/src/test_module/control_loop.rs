@@ -80,11 +80,11 @@ fn create_ipc_context(ipc_type: String, ipc_config: Vec<u8>) -> remote_trait_obj
     if ipc_type == "DomainSocket" {
         let ipc = DomainSocket::new(ipc_config);
         let (send, recv) = ipc.split();
-        remote_trait_object::Context::new(send, recv)
+        remote_trait_object::Context::new(send, recv).unwrap()
     } else if ipc_type == "Intra" {
         let ipc = Intra::new(ipc_config);
         let (send, recv) = ipc.split();
-        remote_trait_object::Context::new(send, recv)
+        remote_trait_object::Context::new(send, recv).unwrap()
     } else {
         panic!("Invalid ipc type")
     }
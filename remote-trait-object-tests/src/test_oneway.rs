@@ -0,0 +1,85 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+use remote_trait_object::*;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[rto_macro::service]
+pub trait Notifier: Service {
+    #[oneway]
+    fn notify(&self, event: u32);
+    fn ping(&self) -> u32;
+}
+
+struct MyNotifier {
+    gate: Receiver<()>,
+    events: Sender<u32>,
+}
+
+impl Service for MyNotifier {}
+
+impl Notifier for MyNotifier {
+    fn notify(&self, event: u32) {
+        if event == 0 {
+            panic!("Invalid event")
+        }
+        self.gate.recv().unwrap();
+        self.events.send(event).unwrap();
+    }
+
+    fn ping(&self) -> u32 {
+        42
+    }
+}
+
+#[test]
+fn oneway_does_not_wait() {
+    // A single call slot would be taken by the first notification, if it waited for the response.
+    let (importer, exporter) =
+        ContextBuilder::new().call_slots(1).call_timeout(Some(Duration::from_secs(1))).build_pair().unwrap();
+    let (gate_send, gate_recv) = bounded(3);
+    let (events_send, events_recv) = bounded(3);
+    exporter.publish::<dyn Notifier>(
+        "notifier",
+        Arc::new(MyNotifier {
+            gate: gate_recv,
+            events: events_send,
+        }),
+    );
+    let notifier = importer.lookup::<dyn Notifier>("notifier").unwrap();
+
+    for event in 1..=3 {
+        notifier.notify(event);
+    }
+    // The handlers are still blocked, but the server has a thread left.
+    assert_eq!(notifier.ping(), 42);
+    for _ in 0..3 {
+        gate_send.send(()).unwrap();
+    }
+    let mut events: Vec<u32> = (0..3).map(|_| events_recv.recv_timeout(Duration::from_secs(1)).unwrap()).collect();
+    events.sort_unstable();
+    assert_eq!(events, vec![1, 2, 3]);
+
+    // Panic of a one-way handler is not reported, and doesn't break the server.
+    notifier.notify(0);
+    assert_eq!(notifier.ping(), 42);
+
+    drop(notifier);
+    drop(importer);
+    drop(exporter);
+}
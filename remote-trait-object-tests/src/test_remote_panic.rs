@@ -0,0 +1,62 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use remote_trait_object::*;
+use std::sync::Arc;
+
+#[rto_macro::service]
+pub trait Fragile: Service {
+    fn explode(&self, message: &str) -> Result<(), remote_trait_object::Error>;
+    fn answer(&self) -> u32;
+}
+
+struct MyFragile;
+
+impl Service for MyFragile {}
+
+impl Fragile for MyFragile {
+    fn explode(&self, message: &str) -> Result<(), Error> {
+        panic!("{}", message)
+    }
+
+    fn answer(&self) -> u32 {
+        42
+    }
+}
+
+#[test]
+fn remote_panic_is_returned_as_error() {
+    let (importer, exporter) = Context::pair().unwrap();
+
+    let handle = export_service!(Fragile, exporter, Arc::new(MyFragile) as Arc<dyn Fragile>);
+    let fragile = import_service!(Fragile, importer, handle);
+
+    // Panic of every handler thread must be survived
+    for _ in 0..8 {
+        match fragile.explode("boom") {
+            Err(Error::RemotePanic {
+                message,
+                ..
+            }) => assert_eq!(message, "boom"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+    assert_eq!(fragile.answer(), 42);
+
+    drop(fragile);
+    drop(importer);
+    drop(exporter);
+}
@@ -0,0 +1,136 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+use remote_trait_object::*;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[rto_macro::service]
+pub trait Gate: Service {
+    fn pass(&self) -> Result<u32, remote_trait_object::Error>;
+}
+
+struct MyGate {
+    entered: Sender<()>,
+    open: Receiver<()>,
+}
+
+impl Service for MyGate {}
+
+impl Gate for MyGate {
+    fn pass(&self) -> Result<u32, Error> {
+        self.entered.send(()).unwrap();
+        self.open.recv().unwrap();
+        Ok(1)
+    }
+}
+
+/// A gate exported by the first context and imported by the second one.
+/// Its calls block until the returned sender is sent a message or dropped.
+fn gate_pair(exporter: ContextBuilder) -> (Context, Context, Gated, Sender<()>) {
+    let (exporter, importer) = exporter.build_pair_with(ContextBuilder::new()).unwrap();
+    let (entered_send, entered_recv) = bounded(1);
+    let (open_send, open_recv) = bounded(1);
+    exporter.publish::<dyn Gate>(
+        "gate",
+        Arc::new(MyGate {
+            entered: entered_send,
+            open: open_recv,
+        }),
+    );
+    let gate = importer.lookup::<dyn Gate>("gate").unwrap();
+    (
+        exporter,
+        importer,
+        Gated {
+            gate,
+            entered: entered_recv,
+        },
+        open_send,
+    )
+}
+
+/// The imported gate, with the signal of each call that has reached the exporter.
+struct Gated {
+    gate: Arc<dyn Gate>,
+    entered: Receiver<()>,
+}
+
+/// Returns once the call is blocked in the exporter.
+fn call_in_background(gated: Gated) -> thread::JoinHandle<Result<u32, Error>> {
+    let gate = gated.gate;
+    let call = thread::spawn(move || gate.pass());
+    gated.entered.recv().unwrap();
+    call
+}
+
+#[test]
+fn fail_pending_calls() {
+    let (disconnected_send, disconnected_recv) = bounded(1);
+    let builder = ContextBuilder::new().on_disconnect(move || disconnected_send.send(()).unwrap());
+    let (exporter, importer, gate, open) = gate_pair(builder);
+    let call = call_in_background(gate);
+
+    let report = importer.shutdown(Instant::now() + Duration::from_secs(1));
+    assert_eq!(call.join().unwrap(), Err(Error::ShuttingDown));
+    assert_eq!(report, ShutdownReport {
+        unfinished_requests: 0,
+        failed_calls: 1,
+        detached_threads: false,
+    });
+    // The goodbye tells the exporter that the importer is gone.
+    disconnected_recv.recv_timeout(Duration::from_secs(1)).unwrap();
+
+    drop(open);
+    assert!(exporter.shutdown(Instant::now() + Duration::from_secs(1)).is_clean());
+}
+
+#[test]
+fn drain_requests() {
+    let (exporter, importer, gate, open) = gate_pair(ContextBuilder::new());
+    let call = call_in_background(gate);
+
+    let opener = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        open.send(()).unwrap();
+    });
+    let report = exporter.shutdown(Instant::now() + Duration::from_secs(1));
+    assert_eq!(report, ShutdownReport::default());
+    // The response is sent before the goodbye.
+    assert_eq!(call.join().unwrap(), Ok(1));
+
+    opener.join().unwrap();
+    drop(importer);
+}
+
+#[test]
+fn unfinished_requests() {
+    let (exporter, importer, gate, open) = gate_pair(ContextBuilder::new());
+    let call = call_in_background(gate);
+
+    // The handler is blocked until the gate is opened, so it can't finish by any deadline.
+    let report = exporter.shutdown(Instant::now() + Duration::from_millis(50));
+    assert_eq!(report.unfinished_requests, 1);
+    assert!(report.detached_threads);
+    assert!(!report.is_clean());
+    assert_eq!(call.join().unwrap(), Err(Error::ConnectionLost));
+
+    // Let the detached handler finish
+    drop(open);
+    drop(importer);
+}
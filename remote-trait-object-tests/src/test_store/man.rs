@@ -1,6 +1,7 @@
 use super::store::run_store;
 use super::types::*;
 use crossbeam::channel::bounded;
+use remote_trait_object::ipc::{IpcRecv, IpcSend};
 use remote_trait_object::*;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Barrier};
@@ -23,24 +24,28 @@ impl CreditCard for MyCreditCard {
 impl Service for MyCreditCard {}
 
 fn test_runner(f: impl Fn(Arc<dyn Store>)) {
-    let crate::ipc::IpcEnds {
-        recv1,
-        send1,
-        recv2,
-        send2,
-    } = crate::ipc::create();
-
-    let (export_send, export_recv) = bounded(100);
+    test_runner_with(remote_trait_object::ipc::intra::pair(), f)
+}
+
+fn test_runner_with<S1, R1, S2, R2>(ends: ((S1, R1), (S2, R2)), f: impl Fn(Arc<dyn Store>))
+where
+    S1: IpcSend + 'static,
+    R1: IpcRecv + 'static,
+    S2: IpcSend + 'static,
+    R2: IpcRecv + 'static, {
+    let ((send1, recv1), (send2, recv2)) = ends;
+
+    let (ready_send, ready_recv) = bounded(0);
     let (signal_send, signal_recv) = bounded(0);
 
     let store_runner = std::thread::Builder::new()
         .name("Store Runner".to_owned())
-        .spawn(move || run_store((send2, recv2), export_send, signal_recv))
+        .spawn(move || run_store((send2, recv2), ready_send, signal_recv))
         .unwrap();
 
-    let rto_context = Context::new(send1, recv1);
-    let store_handle: HandleToExchange = serde_cbor::from_slice(&export_recv.recv().unwrap()).unwrap();
-    let store = import_service!(Store, rto_context, store_handle);
+    let rto_context = Context::new(send1, recv1).unwrap();
+    ready_recv.recv().unwrap();
+    let store = rto_context.lookup::<dyn Store>("store").unwrap();
 
     f(store);
 
@@ -112,3 +117,92 @@ fn test_order3() {
     }
     test_runner(f);
 }
+
+#[cfg(unix)]
+#[test]
+fn test_order_over_unix_socket() {
+    fn f(store: Arc<dyn Store>) {
+        let card = Arc::new(MyCreditCard {
+            balance: AtomicU32::new(11),
+        }) as Arc<dyn CreditCard>;
+        assert_eq!(store.order_coke("Cherry", 4), "Here's a Cherry coke");
+        assert_eq!(store.order_pizza_credit_card(Pizza::Veggie, SArc::new(card)), "Here's a delicious veggie pizza");
+    }
+    test_runner_with(remote_trait_object::ipc::unix::pair().unwrap(), f);
+}
+
+#[test]
+fn lookup_unknown_name() {
+    let (context1, context2) = Context::pair().unwrap();
+    context1.publish::<dyn CreditCard>(
+        "card",
+        Arc::new(MyCreditCard {
+            balance: AtomicU32::new(11),
+        }),
+    );
+    assert_eq!(context2.lookup::<dyn CreditCard>("cart").err(), Some(Error::NameNotFound("cart".to_owned())));
+
+    // Each lookup has its own handle
+    let card1 = context2.lookup::<dyn CreditCard>("card").unwrap();
+    let card2 = context2.lookup::<dyn CreditCard>("card").unwrap();
+    assert_eq!(card1.pay(10), Ok(()));
+    drop(card1);
+    assert_eq!(card2.pay(10), Err(()));
+    assert_eq!(card2.pay(1), Ok(()));
+
+    assert!(context1.unpublish("card"));
+    assert_eq!(context2.lookup::<dyn CreditCard>("card").err(), Some(Error::NameNotFound("card".to_owned())));
+    drop(card2);
+}
+
+#[test]
+fn import_twice() {
+    let (context1, context2) = Context::pair().unwrap();
+    context1.publish::<dyn CreditCard>(
+        "card",
+        Arc::new(MyCreditCard {
+            balance: AtomicU32::new(11),
+        }),
+    );
+
+    // Both hold a reference to the same object, so dropping one leaves the other valid.
+    let handle = context2.lookup_handle("card").unwrap();
+    let card1 = <dyn CreditCard as ImportService<dyn CreditCard>>::import(context2.get_port(), handle);
+    let card2 = <dyn CreditCard as ImportService<dyn CreditCard>>::import(context2.get_port(), handle);
+    assert_eq!(card1.pay(10), Ok(()));
+    drop(card1);
+    assert_eq!(card2.pay(1), Ok(()));
+    assert_eq!(card2.pay(1), Err(()));
+    drop(card2);
+}
+
+#[test]
+fn pass_remote_to_third_context() {
+    fn f(store: Arc<dyn Store>) {
+        let (bank, man) = ContextBuilder::new().server_threads(1).build_pair().unwrap();
+        let card = Arc::new(MyCreditCard {
+            balance: AtomicU32::new(18),
+        });
+        bank.publish::<dyn CreditCard>("card", card.clone() as Arc<dyn CreditCard>);
+        let remote_card = man.lookup::<dyn CreditCard>("card").unwrap();
+
+        // The store pays through the man, who holds the handle to the card of the bank.
+        assert_eq!(
+            store.order_pizza_credit_card(Pizza::Veggie, SArc::new(remote_card.clone())),
+            "Here's a delicious veggie pizza"
+        );
+        assert_eq!(
+            store.order_pizza_credit_card(Pizza::Veggie, SArc::new(remote_card.clone())),
+            "Here's a delicious veggie pizza"
+        );
+        assert_eq!(card.balance.load(Ordering::SeqCst), 0);
+
+        // The store has dropped its handles, which leaves the one of the man valid.
+        assert_eq!(remote_card.pay(0), Ok(()));
+        assert!(bank.unpublish("card"));
+        drop(remote_card);
+        // The last handle deletes the object on the bank.
+        assert_eq!(Arc::strong_count(&card), 1);
+    }
+    test_runner(f);
+}
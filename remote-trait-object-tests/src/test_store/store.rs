@@ -1,6 +1,6 @@
 use super::types::*;
-use crate::ipc::{IntraRecv, IntraSend};
 use crossbeam::channel::{Receiver, Sender};
+use remote_trait_object::ipc::{IpcRecv, IpcSend};
 use remote_trait_object::*;
 use std::sync::Arc;
 
@@ -54,13 +54,17 @@ impl Store for MyPizzaStore {
 
 impl Service for MyPizzaStore {}
 
-pub fn run_store(ipc: (IntraSend, IntraRecv), export_channel: Sender<Vec<u8>>, end_signal: Receiver<()>) {
+pub fn run_store<S: IpcSend + 'static, R: IpcRecv + 'static>(
+    ipc: (S, R),
+    ready_signal: Sender<()>,
+    end_signal: Receiver<()>,
+) {
     let (ipc_send, ipc_recv) = ipc;
-    let rto_context = Context::new(ipc_send, ipc_recv);
+    let rto_context = Context::new(ipc_send, ipc_recv).unwrap();
     let store = Arc::new(MyPizzaStore {
         vat: 1,
     }) as Arc<dyn Store>;
-    let handle = export_service!(Store, rto_context, store);
-    export_channel.send(serde_cbor::to_vec(&handle).unwrap()).unwrap();
+    rto_context.publish::<dyn Store>("store", store);
+    ready_signal.send(()).unwrap();
     end_signal.recv().unwrap();
 }
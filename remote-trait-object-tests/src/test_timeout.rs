@@ -0,0 +1,89 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use remote_trait_object::*;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[rto_macro::service]
+pub trait Sleeper: Service {
+    fn sleep(&self, ms: u64) -> Result<u64, remote_trait_object::Error>;
+    #[timeout_ms = 50]
+    fn sleep_briefly(&self, ms: u64) -> Result<u64, remote_trait_object::Error>;
+}
+
+struct MySleeper;
+
+impl Service for MySleeper {}
+
+impl Sleeper for MySleeper {
+    fn sleep(&self, ms: u64) -> Result<u64, Error> {
+        thread::sleep(Duration::from_millis(ms));
+        Ok(ms)
+    }
+
+    fn sleep_briefly(&self, ms: u64) -> Result<u64, Error> {
+        self.sleep(ms)
+    }
+}
+
+/// Runs `f` with a sleeper imported through a context of a single call slot,
+/// so that every call reuses the slot of the previous one.
+fn test_runner(call_timeout: Option<Duration>, f: impl FnOnce(&Context, HandleToExchange)) {
+    let (importer, exporter) = ContextBuilder::new().call_slots(1).call_timeout(call_timeout).build_pair().unwrap();
+    let handle = export_service!(Sleeper, exporter, Arc::new(MySleeper) as Arc<dyn Sleeper>);
+
+    f(&importer, handle);
+
+    // Let the late responses arrive before shutdown
+    thread::sleep(Duration::from_millis(200));
+    drop(importer);
+    drop(exporter);
+}
+
+#[test]
+fn method_timeout() {
+    test_runner(None, |importer, handle| {
+        let sleeper = import_service!(Sleeper, importer, handle);
+        assert_eq!(sleeper.sleep(100), Ok(100));
+        assert_eq!(sleeper.sleep_briefly(100), Err(Error::Timeout));
+        // The late response of the previous call must not be taken as the response of this call.
+        assert_eq!(sleeper.sleep(0), Ok(0));
+        assert_eq!(sleeper.sleep_briefly(1), Ok(1));
+    });
+}
+
+#[test]
+fn context_timeout() {
+    test_runner(Some(Duration::from_millis(50)), |importer, handle| {
+        let sleeper = import_service!(Sleeper, importer, handle);
+        assert_eq!(sleeper.sleep(100), Err(Error::Timeout));
+        assert_eq!(sleeper.sleep(0), Ok(0));
+    });
+}
+
+#[test]
+fn handle_timeout() {
+    test_runner(None, |importer, handle| {
+        // Method overrides the handle
+        let sleeper = import_service!(Sleeper, importer, handle, Duration::from_millis(300));
+        assert_eq!(sleeper.sleep_briefly(100), Err(Error::Timeout));
+        assert_eq!(sleeper.sleep(100), Ok(100));
+        assert_eq!(sleeper.sleep(400), Err(Error::Timeout));
+        assert_eq!(sleeper.sleep(0), Ok(0));
+    });
+}
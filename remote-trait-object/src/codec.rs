@@ -0,0 +1,192 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Formats of the arguments and the return values of the remote calls.
+//! The requests to the port itself, like the handshake and the lookup, are always in CBOR.
+
+use crate::Error;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+
+pub trait Codec {
+    /// The `Format` that selects this at runtime.
+    const FORMAT: Format;
+
+    /// Serializes right into the writer, like a `PacketBuilder`, without an intermediate buffer.
+    fn encode_into<T: Serialize, W: io::Write>(value: &T, writer: W) -> Result<(), Error>;
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, Error>;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+        let mut buffer = Vec::new();
+        Self::encode_into(value, &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+pub struct Cbor;
+
+impl Codec for Cbor {
+    const FORMAT: Format = Format::Cbor;
+
+    fn encode_into<T: Serialize, W: io::Write>(value: &T, writer: W) -> Result<(), Error> {
+        serde_cbor::to_writer(writer, value).map_err(|err| Error::SerializationFailed(err.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, Error> {
+        serde_cbor::from_slice(data).map_err(|err| Error::DeserializationFailed(err.to_string()))
+    }
+}
+
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Bincode {
+    /// Integers in a fixed size, and the bytes after the value are ignored.
+    /// `Format::Bincode` on the wire is what `bincode::serialize` writes, so these must stay bit-compatible with it,
+    /// rather than take the varint integers of `DefaultOptions`.
+    fn options() -> impl bincode::Options {
+        use bincode::Options;
+        bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes()
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    const FORMAT: Format = Format::Bincode;
+
+    fn encode_into<T: Serialize, W: io::Write>(value: &T, writer: W) -> Result<(), Error> {
+        use bincode::Options;
+        Self::options().serialize_into(writer, value).map_err(|err| Error::SerializationFailed(err.to_string()))
+    }
+
+    /// A length in the data can't make it allocate more than the data itself.
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, Error> {
+        use bincode::Options;
+        Self::options()
+            .with_limit(data.len() as u64)
+            .deserialize(data)
+            .map_err(|err| Error::DeserializationFailed(err.to_string()))
+    }
+}
+
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    const FORMAT: Format = Format::Json;
+
+    fn encode_into<T: Serialize, W: io::Write>(value: &T, writer: W) -> Result<(), Error> {
+        serde_json::to_writer(writer, value).map_err(|err| Error::SerializationFailed(err.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, Error> {
+        serde_json::from_slice(data).map_err(|err| Error::DeserializationFailed(err.to_string()))
+    }
+}
+
+/// Selects a `Codec` at runtime.
+/// Every format can be named, but only the ones enabled by the cargo features can be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Format {
+    #[default]
+    Cbor,
+    Bincode,
+    Json,
+}
+
+impl Format {
+    pub fn is_enabled(self) -> bool {
+        match self {
+            Format::Cbor => true,
+            Format::Bincode => cfg!(feature = "bincode"),
+            Format::Json => cfg!(feature = "json"),
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, Error> {
+        let mut buffer = Vec::new();
+        self.encode_into(value, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    pub fn encode_into<T: Serialize, W: io::Write>(self, value: &T, writer: W) -> Result<(), Error> {
+        match self {
+            Format::Cbor => Cbor::encode_into(value, writer),
+            #[cfg(feature = "bincode")]
+            Format::Bincode => Bincode::encode_into(value, writer),
+            #[cfg(feature = "json")]
+            Format::Json => Json::encode_into(value, writer),
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::SerializationFailed(format!("{} is not enabled", self))),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, data: &[u8]) -> Result<T, Error> {
+        match self {
+            Format::Cbor => Cbor::decode(data),
+            #[cfg(feature = "bincode")]
+            Format::Bincode => Bincode::decode(data),
+            #[cfg(feature = "json")]
+            Format::Json => Json::decode(data),
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::DeserializationFailed(format!("{} is not enabled", self))),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Cbor => write!(f, "cbor"),
+            Format::Bincode => write!(f, "bincode"),
+            Format::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let value = (3_u32, "three".to_owned(), vec![Some(3_u8), None], Ok::<_, ()>(3.0_f64));
+        for format in [Format::Cbor, Format::Bincode, Format::Json].iter().filter(|format| format.is_enabled()) {
+            let data = format.encode(&value).unwrap();
+            assert_eq!(format.decode::<(u32, String, Vec<Option<u8>>, Result<f64, ()>)>(&data).unwrap(), value);
+        }
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_length_beyond_data() {
+        let mut data = Bincode::encode(&vec![3_u8; 4]).unwrap();
+        data[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(Bincode::decode::<Vec<u8>>(&data), Err(Error::DeserializationFailed(_))));
+    }
+
+    #[test]
+    fn disabled_format() {
+        for format in [Format::Bincode, Format::Json].iter().filter(|format| !format.is_enabled()) {
+            assert!(format.encode(&3).is_err());
+            assert!(format.decode::<u32>(&[]).is_err());
+        }
+    }
+}
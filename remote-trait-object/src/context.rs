@@ -14,34 +14,365 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::ipc::multiplex::{self, ForwardResult, MultiplexResult, Multiplexer};
-use crate::ipc::{IpcRecv, IpcSend};
-use crate::packet::{PacketView, SlotType};
-use crate::port::{client::Client, server::Server, BasicPort, Port};
+use crate::codec::Format;
+use crate::forwarder::{ServiceForwarder, GOODBYE, HANDSHAKE, LOOKUP, PING, PONG};
+use crate::interceptor::Interceptor;
+use crate::ipc::multiplex::{self, ForwardResult, Heartbeat, Liveness, MultiplexResult, Multiplexer, Termination};
+use crate::ipc::{intra, IpcRecv, IpcSend};
+use crate::packet::{Packet, PacketView, SlotType};
+use crate::port::client::{Client, MAX_CALL_SLOTS};
+use crate::port::handshake::{self, PeerMethods};
+#[cfg(feature = "async")]
+use crate::port::server::Executor;
+use crate::port::{server::Server, BasicPort, Port};
+#[cfg(feature = "async")]
+use crate::service::async_dispatch::ExportAsyncService;
+use crate::service::id::{check_collision, IdMap};
+use crate::service::{ExportService, HandleToExchange, ImportService, Service};
+use crate::Error;
+use std::fmt;
 use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+/// A function of the user, which is called on a thread of the context.
+#[derive(Clone)]
+pub(crate) struct Callback(Arc<dyn Fn() + Send + Sync>);
+
+impl fmt::Debug for Callback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Callback")
+    }
+}
+
+/// Runtime parameters of a `Context`. Use `ContextBuilder` to set them.
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    pub call_slots: usize,
+    pub call_timeout: Option<Duration>,
+    pub server_threads: usize,
+    pub server_queue_size: usize,
+    pub multiplexer_channel_size: usize,
+    pub client_shutdown_timeout: Duration,
+    pub server_shutdown_timeout: Duration,
+    pub handshake_timeout: Option<Duration>,
+    pub id_map: IdMap,
+    pub format: Format,
+    #[cfg(feature = "async")]
+    pub executor: Option<Arc<dyn Executor>>,
+    pub on_disconnect: Option<Callback>,
+    pub heartbeat: Option<Heartbeat>,
+    pub on_peer_lost: Option<Callback>,
+    pub interceptors: Vec<Arc<dyn Interceptor>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            call_slots: 100,
+            call_timeout: None,
+            server_threads: 4,
+            server_queue_size: 100,
+            multiplexer_channel_size: 1,
+            client_shutdown_timeout: Duration::from_millis(100),
+            server_shutdown_timeout: Duration::from_millis(500),
+            handshake_timeout: Some(Duration::from_secs(10)),
+            id_map: Default::default(),
+            format: Default::default(),
+            #[cfg(feature = "async")]
+            executor: None,
+            on_disconnect: None,
+            heartbeat: None,
+            on_peer_lost: None,
+            interceptors: Vec::new(),
+        }
+    }
+}
+
+/// Builds a `Context` with the runtime parameters other than the default.
+/// A sandboxed module may want fewer threads, while a busy host may want more slots and threads.
+#[derive(Debug, Clone, Default)]
+pub struct ContextBuilder {
+    config: Config,
+}
+
+impl ContextBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The maximum number of calls that can be made to the counterparty at the same time.
+    /// A call beyond this waits for the others to finish. It must be in 1..=65536.
+    pub fn call_slots(mut self, call_slots: usize) -> Self {
+        assert!(
+            call_slots > 0 && call_slots <= MAX_CALL_SLOTS,
+            "The number of call slots must be in 1..={}",
+            MAX_CALL_SLOTS
+        );
+        self.config.call_slots = call_slots;
+        self
+    }
+
+    /// The timeout of a call, unless the handle or the method specifies it. None means no timeout.
+    pub fn call_timeout(mut self, call_timeout: Option<Duration>) -> Self {
+        self.config.call_timeout = call_timeout;
+        self
+    }
+
+    /// The number of threads that handle the calls from the counterparty.
+    pub fn server_threads(mut self, server_threads: usize) -> Self {
+        assert!(server_threads > 0, "Server needs at least one thread");
+        self.config.server_threads = server_threads;
+        self
+    }
+
+    /// The number of calls from the counterparty that can wait for a server thread.
+    pub fn server_queue_size(mut self, server_queue_size: usize) -> Self {
+        self.config.server_queue_size = server_queue_size;
+        self
+    }
+
+    /// The capacity of the channels between the multiplexer and the client/server.
+    pub fn multiplexer_channel_size(mut self, multiplexer_channel_size: usize) -> Self {
+        self.config.multiplexer_channel_size = multiplexer_channel_size;
+        self
+    }
+
+    /// How long to wait for the client thread to be joined when the context is dropped.
+    /// The thread is detached if it doesn't finish by then.
+    pub fn client_shutdown_timeout(mut self, client_shutdown_timeout: Duration) -> Self {
+        self.config.client_shutdown_timeout = client_shutdown_timeout;
+        self
+    }
+
+    /// How long to wait for the calls from the counterparty to finish, and the server threads to be joined,
+    /// when the context is dropped. The threads are detached if they don't finish by then.
+    pub fn server_shutdown_timeout(mut self, server_shutdown_timeout: Duration) -> Self {
+        self.config.server_shutdown_timeout = server_shutdown_timeout;
+        self
+    }
+
+    /// How long to wait for the handshake packet of the counterparty when the context is built.
+    /// None means to wait until the counterparty sends it or closes the connection.
+    pub fn handshake_timeout(mut self, handshake_timeout: Option<Duration>) -> Self {
+        self.config.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    /// Method ids that this context dispatches, instead of the defaults of the process.
+    /// The counterparty learns them in the handshake, so it can be built with other ids.
+    /// A method that the map doesn't have keeps its default id.
+    pub fn id_map(mut self, id_map: IdMap) -> Self {
+        check_collision(|trait_name, method_name| {
+            id_map.method_id(trait_name, method_name).expect("It is in the registry")
+        });
+        self.config.id_map = id_map;
+        self
+    }
+
+    /// The format that the service objects of this context take, unless their trait specifies it.
+    /// The counterparty learns it in the handshake, so the two contexts may take different formats.
+    pub fn format(mut self, format: Format) -> Self {
+        assert!(format.is_enabled(), "Enable the {} feature to use it", format);
+        self.config.format = format;
+        self
+    }
+
+    /// Calls to async service objects are run by the executor, rather than blocking the server threads.
+    /// The server threads still handle the other calls.
+    #[cfg(feature = "async")]
+    pub fn executor(mut self, executor: Arc<dyn Executor>) -> Self {
+        self.config.executor = Some(executor);
+        self
+    }
+
+    /// Called when the counterparty closes the connection, or it is lost by the heartbeat,
+    /// after the objects exported to it are released.
+    /// The calls to the counterparty fail with `Error::ConnectionLost` from then on.
+    /// It is not called when this context is dropped.
+    /// It runs on the thread that receives from the counterparty, so it should return soon.
+    pub fn on_disconnect<F: Fn() + Send + Sync + 'static>(mut self, on_disconnect: F) -> Self {
+        self.config.on_disconnect = Some(Callback(Arc::new(on_disconnect)));
+        self
+    }
+
+    /// Pings the counterparty when nothing is heard from it for `interval`,
+    /// and takes it as lost when it doesn't answer `max_misses` pings in a row.
+    /// A lost counterparty is handled like a closed connection, and `on_peer_lost` is called after `on_disconnect`.
+    /// By default there is no heartbeat, and a hung counterparty is not noticed.
+    pub fn heartbeat(mut self, interval: Duration, max_misses: u32) -> Self {
+        assert!(max_misses > 0, "Heartbeat needs at least one miss to take the counterparty as lost");
+        self.config.heartbeat = Some(Heartbeat {
+            interval,
+            max_misses,
+        });
+        self
+    }
+
+    /// Called when the counterparty doesn't answer the heartbeat. See `heartbeat`.
+    /// It runs on the thread that receives from the counterparty, so it should return soon.
+    pub fn on_peer_lost<F: Fn() + Send + Sync + 'static>(mut self, on_peer_lost: F) -> Self {
+        self.config.on_peer_lost = Some(Callback(Arc::new(on_peer_lost)));
+        self
+    }
+
+    /// Adds an interceptor of the calls to service objects, both to and from the counterparty.
+    /// Interceptors see a call in the order that they are added, and see its end in the reverse order.
+    pub fn interceptor(mut self, interceptor: Arc<dyn Interceptor>) -> Self {
+        self.config.interceptors.push(interceptor);
+        self
+    }
+
+    /// Builds the context, which returns after the handshake with the counterparty.
+    /// So the counterparty must be built at the same time, by another process or thread.
+    pub fn build<S: IpcSend + 'static, R: IpcRecv + 'static>(self, ipc_send: S, ipc_recv: R) -> Result<Context, Error> {
+        Context::with_config(self.config, ipc_send, ipc_recv)
+    }
+
+    /// Two contexts connected to each other in this process. Both have the same parameters.
+    pub fn build_pair(self) -> Result<(Context, Context), Error> {
+        self.clone().build_pair_with(self)
+    }
+
+    /// Two contexts connected to each other in this process, the second of which is built by `other`.
+    pub fn build_pair_with(self, other: ContextBuilder) -> Result<(Context, Context), Error> {
+        let ((send1, recv1), (send2, recv2)) = intra::pair();
+        let context1 = Context::start(self.config, send1, recv1);
+        let context2 = Context::start(other.config, send2, recv2);
+        Ok((context1.handshake()?, context2.handshake()?))
+    }
+}
+
+/// What happened in `Context::shutdown`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ShutdownReport {
+    /// Calls from the counterparty that were still running at the deadline. Their responses are lost.
+    pub unfinished_requests: usize,
+    /// Calls to the counterparty that were failed with `Error::ShuttingDown`.
+    pub failed_calls: usize,
+    /// Threads of the context that didn't finish by the deadline. They are left detached.
+    pub detached_threads: bool,
+}
+
+/// The state of the connection to the counterparty, from `Context::health`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Health {
+    /// The counterparty answers, or the heartbeat is disabled and the connection is open.
+    Alive,
+    /// The counterparty didn't answer this number of the pings in a row, which is less than the limit.
+    Unresponsive(u32),
+    /// The counterparty closed the connection.
+    Disconnected,
+    /// The counterparty didn't answer the heartbeat, so the connection is dropped.
+    Lost,
+}
+
+impl ShutdownReport {
+    /// Every call from the counterparty was answered, and every thread finished in time.
+    pub fn is_clean(&self) -> bool {
+        self.unfinished_requests == 0 && !self.detached_threads
+    }
+}
 
 pub struct Context {
     multiplexer: Option<Multiplexer>,
     server: Option<Server>,
     port: Option<Arc<BasicPort>>,
+    liveness: Arc<Liveness>,
+    client_shutdown_timeout: Duration,
+    server_shutdown_timeout: Duration,
 }
 
 impl Context {
-    pub fn new<S: IpcSend + 'static, R: IpcRecv + 'static>(ipc_send: S, ipc_recv: R) -> Self {
+    /// Builds a context with the default parameters. See `ContextBuilder::build`.
+    pub fn new<S: IpcSend + 'static, R: IpcRecv + 'static>(ipc_send: S, ipc_recv: R) -> Result<Self, Error> {
+        ContextBuilder::new().build(ipc_send, ipc_recv)
+    }
+
+    /// Two contexts connected to each other in this process.
+    pub fn pair() -> Result<(Self, Self), Error> {
+        ContextBuilder::new().build_pair()
+    }
+
+    fn with_config<S: IpcSend + 'static, R: IpcRecv + 'static>(
+        config: Config,
+        ipc_send: S,
+        ipc_recv: R,
+    ) -> Result<Self, Error> {
+        Self::start(config, ipc_send, ipc_recv).handshake()
+    }
+
+    /// Waits for the handshake of the counterparty. The context is dropped if it fails.
+    fn handshake(self) -> Result<Self, Error> {
+        self.port().handshake()?;
+        Ok(self)
+    }
+
+    /// Starts the context, which sends its own handshake packet but doesn't wait for the counterparty's.
+    fn start<S: IpcSend + 'static, R: IpcRecv + 'static>(config: Config, ipc_send: S, ipc_recv: R) -> Self {
+        let registry = Arc::new(ServiceForwarder::new());
+        let on_termination = {
+            let registry = Arc::clone(&registry);
+            let on_disconnect = config.on_disconnect.clone();
+            let on_peer_lost = config.on_peer_lost.clone();
+            Box::new(move |termination| {
+                // No one is left to delete the objects exported to the counterparty.
+                registry.release_all();
+                if let Some(Callback(on_disconnect)) = on_disconnect {
+                    on_disconnect();
+                }
+                if let (Termination::Unresponsive, Some(Callback(on_peer_lost))) = (termination, on_peer_lost) {
+                    on_peer_lost();
+                }
+            })
+        };
         let MultiplexResult {
             multiplexer,
             request_recv,
             response_recv,
+            handshake_recv,
             multiplexed_send,
-        } = Multiplexer::multiplex::<R, S, PacketForward>(ipc_send, ipc_recv);
-        let client = Client::new(multiplexed_send.clone(), response_recv);
-        let port = BasicPort::new(client);
-        let server = Server::new(port.get_registry(), multiplexed_send, request_recv);
+            liveness,
+        } = Multiplexer::multiplex::<R, S, PacketForward>(
+            config.multiplexer_channel_size,
+            ipc_send,
+            ipc_recv,
+            config.heartbeat,
+            on_termination,
+        );
+        // This must be the first packet to the counterparty.
+        multiplexed_send
+            .send(handshake::advertisement(&config.id_map, config.format))
+            .expect("Multiplexer has just started");
+        let client = Client::new(&config, multiplexed_send.clone(), response_recv);
+        let port = BasicPort::new(
+            client,
+            registry,
+            PeerMethods::new(handshake_recv, config.handshake_timeout),
+            config.id_map.clone(),
+            config.format,
+            config.interceptors.clone(),
+        );
+        let server = Server::new(&config, port.get_registry(), multiplexed_send, request_recv);
 
         Context {
             multiplexer: Some(multiplexer),
             server: Some(server),
             port: Some(port),
+            liveness,
+            client_shutdown_timeout: config.client_shutdown_timeout,
+            server_shutdown_timeout: config.server_shutdown_timeout,
+        }
+    }
+
+    /// Whether the counterparty is still there. See `ContextBuilder::heartbeat`.
+    pub fn health(&self) -> Health {
+        match self.liveness.termination() {
+            Some(Termination::Closed) => Health::Disconnected,
+            Some(Termination::Unresponsive) => Health::Lost,
+            None => match self.liveness.missed() {
+                0 => Health::Alive,
+                missed => Health::Unresponsive(missed),
+            },
         }
     }
 
@@ -49,20 +380,94 @@ impl Context {
         Arc::downgrade(&self.port.clone().expect("It becomes None only when the context is dropped.")) as Weak<dyn Port>
     }
 
+    /// Lets the counterparty look up the object by the name.
+    /// Each lookup gets its own handle, and the object lives as long as it is published.
+    pub fn publish<T: ?Sized + Service + ExportService<T> + 'static>(&self, name: &str, object: Arc<T>) {
+        self.port().get_registry().publish(name.to_owned(), Box::new(move |port| T::export(port, Arc::clone(&object))))
+    }
+
+    /// Publishes an async service object, which is exported with `#[service(async_dispatch)]`.
+    #[cfg(feature = "async")]
+    pub fn publish_async<T: ?Sized + ExportAsyncService<T> + Send + Sync + 'static>(&self, name: &str, object: Arc<T>) {
+        self.port()
+            .get_registry()
+            .publish(name.to_owned(), Box::new(move |port| T::export_async(port, Arc::clone(&object))))
+    }
+
+    /// The handles that the counterparty already looked up are still valid.
+    pub fn unpublish(&self, name: &str) -> bool {
+        self.port().get_registry().unpublish(name)
+    }
+
+    /// Imports the object that the counterparty published under the name.
+    pub fn lookup<T: ?Sized + Service + ImportService<T>>(&self, name: &str) -> Result<Arc<T>, Error> {
+        Ok(T::import(self.get_port(), self.lookup_handle(name)?))
+    }
+
+    /// Looks up the name like `lookup`, but leaves the import to the caller.
+    /// This is for a remote that is not a trait object, such as the async one.
+    pub fn lookup_handle(&self, name: &str) -> Result<HandleToExchange, Error> {
+        let name_bytes = serde_cbor::to_vec(&name).map_err(|err| Error::SerializationFailed(err.to_string()))?;
+        let packet = Packet::new_request(0, LOOKUP, &name_bytes);
+        let response = self.port().call(packet, None)?;
+        let handle: Option<HandleToExchange> =
+            serde_cbor::from_slice(response.data()).map_err(|err| Error::DeserializationFailed(err.to_string()))?;
+        handle.ok_or_else(|| Error::NameNotFound(name.to_owned()))
+    }
+
+    fn port(&self) -> &Arc<BasicPort> {
+        self.port.as_ref().expect("It becomes None only when the context is dropped.")
+    }
+
     pub fn disable_garbage_collection(&self) {
         self.port.as_ref().expect("It becomes None only when the context is dropped.").set_no_drop();
     }
+
+    /// Stops the context, letting the calls from the counterparty finish until the deadline.
+    ///
+    /// The calls from the counterparty after this, and the calls to the counterparty that are not answered yet,
+    /// fail with `Error::ShuttingDown`. Then the counterparty is told that this side is gone,
+    /// so that it releases the objects that this side imported. Dropping the context does the same,
+    /// with the shutdown timeouts of the `ContextBuilder`.
+    pub fn shutdown(mut self, deadline: Instant) -> ShutdownReport {
+        self.shutdown_by(deadline, deadline)
+    }
+
+    fn shutdown_by(&mut self, server_deadline: Instant, client_deadline: Instant) -> ShutdownReport {
+        let multiplexer = self.multiplexer.take().expect("It becomes None only when the context is shut down.");
+        let server = self.server.take().expect("It becomes None only when the context is shut down.");
+        let port = self.port.take().expect("It becomes None only when the context is shut down.");
+
+        server.close();
+        let unfinished_requests = server.drain(server_deadline);
+        // The goodbye is the last packet to the counterparty.
+        let failed_calls = port.close();
+        multiplexer.shutdown();
+        // Shutdown server and port after multiplexer
+        let server_joined = server.shutdown(server_deadline);
+        let client_joined = port.shutdown(client_deadline);
+        // The counterparty won't call them anymore.
+        port.get_registry().release_all();
+
+        ShutdownReport {
+            unfinished_requests,
+            failed_calls,
+            detached_threads: !server_joined || !client_joined,
+        }
+    }
 }
 
 impl Drop for Context {
     fn drop(&mut self) {
-        self.multiplexer.take().expect("It becomes None only when the context is dropped.").shutdown();
-        // Shutdown server after multiplexer
-        self.server.take().expect("It becomes None only when the context is dropped.").shutdown();
-        // Shutdown port after multiplexer
-        Arc::try_unwrap(self.port.take().expect("It becomes None only when the context is dropped."))
-            .unwrap()
-            .shutdown();
+        if self.multiplexer.is_none() {
+            // It is shut down already.
+            return
+        }
+        let now = Instant::now();
+        let report = self.shutdown_by(now + self.server_shutdown_timeout, now + self.client_shutdown_timeout);
+        if !report.is_clean() {
+            warn!("Context is dropped without finishing: {:?}", report);
+        }
     }
 }
 
@@ -70,7 +475,19 @@ pub struct PacketForward;
 
 impl multiplex::Forward for PacketForward {
     fn forward(packet: PacketView) -> ForwardResult {
-        match packet.slot().get_type() {
+        if packet.method() == HANDSHAKE {
+            return ForwardResult::Handshake
+        }
+        if packet.method() == GOODBYE {
+            return ForwardResult::Goodbye
+        }
+        if packet.method() == PING {
+            return ForwardResult::Ping
+        }
+        if packet.method() == PONG {
+            return ForwardResult::Pong
+        }
+        match packet.slot_type() {
             SlotType::Request => ForwardResult::Request,
             SlotType::Response => ForwardResult::Response,
         }
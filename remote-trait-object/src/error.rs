@@ -0,0 +1,87 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::forwarder::ServiceObjectId;
+use crate::service::MethodId;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Failure of a remote call.
+///
+/// A service trait method can declare `-> Result<T, remote_trait_object::Error>`
+/// to receive these instead of panicking on the importer side.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Error {
+    /// The connection to the counterparty is closed.
+    ConnectionLost,
+    /// The port that the handle belongs to is already dropped.
+    PortDropped,
+    /// Failed to serialize the arguments or the return value.
+    SerializationFailed(String),
+    /// Failed to deserialize the arguments or the return value.
+    DeserializationFailed(String),
+    /// The call didn't finish in time.
+    Timeout,
+    /// This context or the counterparty is shutting down.
+    ShuttingDown,
+    /// The counterparty doesn't have the method.
+    Incompatible {
+        trait_name: String,
+        method_name: String,
+    },
+    /// Nothing is published under the name by the counterparty.
+    NameNotFound(String),
+    /// The counterparty doesn't have the service object. It may have been deleted already.
+    ObjectNotFound(ServiceObjectId),
+    /// The counterparty sent an invalid handshake.
+    HandshakeFailed(String),
+    /// An interceptor rejected the call.
+    Rejected(String),
+    /// The service object panicked, or the exporter failed to dispatch the call.
+    RemotePanic {
+        object_id: ServiceObjectId,
+        method: MethodId,
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ConnectionLost => write!(f, "Connection to the counterparty is lost"),
+            Error::PortDropped => write!(f, "Port is already dropped"),
+            Error::SerializationFailed(msg) => write!(f, "Serialization failed: {}", msg),
+            Error::DeserializationFailed(msg) => write!(f, "Deserialization failed: {}", msg),
+            Error::Timeout => write!(f, "Remote call timed out"),
+            Error::ShuttingDown => write!(f, "Context is shutting down"),
+            Error::Incompatible {
+                trait_name,
+                method_name,
+            } => write!(f, "Counterparty doesn't have method {} of {}", method_name, trait_name),
+            Error::NameNotFound(name) => write!(f, "Counterparty didn't publish {}", name),
+            Error::ObjectNotFound(object_id) => write!(f, "Counterparty doesn't have object {}", object_id),
+            Error::HandshakeFailed(msg) => write!(f, "Handshake failed: {}", msg),
+            Error::Rejected(msg) => write!(f, "Call is rejected: {}", msg),
+            Error::RemotePanic {
+                object_id,
+                method,
+                message,
+            } => write!(f, "Remote call to method {} of object {} panicked: {}", method, object_id, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -14,20 +14,106 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::packet::PacketView;
+#[cfg(feature = "async")]
+use crate::packet::Packet;
+use crate::packet::{PacketBuilder, PacketView};
 use crate::port::{null_weak_port, Handler, Port};
-use crate::service::Dispatch;
-use parking_lot::RwLock;
+#[cfg(feature = "async")]
+use crate::service::async_dispatch::{AsyncDispatch, BoxFuture};
+use crate::service::{Dispatch, HandleToExchange, MethodId};
+use crate::Error;
+use parking_lot::{Mutex, RwLock};
 use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::sync::{Arc, Weak};
 
-pub type ServiceObjectId = u32;
-pub const DELETE_REQUEST: crate::service::MethodId = std::u32::MAX;
+/// Lower 32 bits are the index of the service object, and upper 32 bits are the generation of the index,
+/// which changes every time the index is reused. So a stale handle never reaches a new object.
+pub type ServiceObjectId = u64;
 
+pub fn object_index(id: ServiceObjectId) -> u32 {
+    id as u32
+}
+
+pub fn object_generation(id: ServiceObjectId) -> u32 {
+    (id >> 32) as u32
+}
+
+fn object_id(index: u32, generation: u32) -> ServiceObjectId {
+    u64::from(generation) << 32 | u64::from(index)
+}
+
+/// Indices are reused in FIFO order, each time with the next generation.
+/// A new index is made only when none is free, so the space grows as far as the objects alive.
+#[derive(Debug, Default)]
+struct IdPool {
+    free: VecDeque<ServiceObjectId>,
+    next_index: u32,
+}
+
+impl IdPool {
+    fn allocate(&mut self) -> ServiceObjectId {
+        if let Some(id) = self.free.pop_front() {
+            return id
+        }
+        let index = self.next_index;
+        self.next_index = index.checked_add(1).expect("Too many service objects are alive");
+        object_id(index, 0)
+    }
+
+    fn release(&mut self, id: ServiceObjectId) {
+        self.free.push_back(object_id(object_index(id), object_generation(id).wrapping_add(1)));
+    }
+}
+/// Method ids from this are reserved for the requests to the port itself, rather than to a service object.
+pub const RESERVED_METHOD_ID_START: crate::service::MethodId = 0xffff_0000;
+pub const DELETE_REQUEST: crate::service::MethodId = u32::MAX;
+/// The first packet of a connection, which advertises the method ids of the sender.
+pub const HANDSHAKE: crate::service::MethodId = u32::MAX - 1;
+/// Asks for a new handle to the service object published under the name.
+pub const LOOKUP: crate::service::MethodId = u32::MAX - 2;
+/// The last packet of a side that is shutting down.
+pub const GOODBYE: crate::service::MethodId = u32::MAX - 3;
+/// Asks the counterparty to answer with `PONG`, to tell that it is alive. The multiplexer answers it.
+pub const PING: crate::service::MethodId = u32::MAX - 4;
+pub const PONG: crate::service::MethodId = u32::MAX - 5;
+
+pub fn is_port_request(method: crate::service::MethodId) -> bool {
+    method == DELETE_REQUEST
+        || method == HANDSHAKE
+        || method == LOOKUP
+        || method == GOODBYE
+        || method == PING
+        || method == PONG
+}
+
+/// Exports the published object again for each lookup,
+/// so that the counterparty deletes only its own handle when it drops the imported object.
+pub type Publication = Box<dyn Fn(Weak<dyn Port>) -> HandleToExchange + Send + Sync>;
+
+#[derive(Clone)]
+enum ServiceObject {
+    Sync(Arc<dyn Dispatch>),
+    #[cfg(feature = "async")]
+    Async(Arc<dyn AsyncDispatch>),
+}
+
+impl ServiceObject {
+    fn method_name(&self, method: MethodId) -> Option<(&'static str, &'static str)> {
+        match self {
+            ServiceObject::Sync(object) => object.method_name(method),
+            #[cfg(feature = "async")]
+            ServiceObject::Async(object) => object.method_name(method),
+        }
+    }
+}
+
+/// The exported service objects. The counterparty counts the handles to each,
+/// and sends a single delete request when it drops the last one.
 pub struct ServiceForwarder {
-    service_objects: RwLock<HashMap<ServiceObjectId, Arc<dyn Dispatch>>>,
-    available_ids: RwLock<VecDeque<ServiceObjectId>>,
+    service_objects: RwLock<HashMap<ServiceObjectId, ServiceObject>>,
+    ids: Mutex<IdPool>,
+    publications: RwLock<HashMap<String, Publication>>,
     port: RwLock<Weak<dyn Port>>,
 }
 
@@ -41,46 +127,101 @@ impl ServiceForwarder {
     pub fn new() -> Self {
         Self {
             service_objects: Default::default(),
-            available_ids: RwLock::new({
-                let mut queue = VecDeque::new();
-                for i in 0..100 {
-                    queue.push_back(i)
-                }
-                queue
-            }),
+            ids: Default::default(),
+            publications: Default::default(),
             port: RwLock::new(null_weak_port()),
         }
     }
 
     pub fn register_service_object(&self, service_object: Arc<dyn Dispatch>) -> ServiceObjectId {
-        let id = self.available_ids.write().pop_front().expect("Too many service objects had been created");
+        self.register(ServiceObject::Sync(service_object))
+    }
+
+    #[cfg(feature = "async")]
+    pub fn register_async_service_object(&self, service_object: Arc<dyn AsyncDispatch>) -> ServiceObjectId {
+        self.register(ServiceObject::Async(service_object))
+    }
+
+    fn register(&self, service_object: ServiceObject) -> ServiceObjectId {
+        let id = self.ids.lock().allocate();
         assert!(self.service_objects.write().insert(id, service_object).is_none());
         id
     }
 
-    pub fn forward_and_call(&self, packet: PacketView) -> Vec<u8> {
+    /// A call to a service object that doesn't exist is answered with `Error::ObjectNotFound`.
+    pub fn forward_and_call(&self, packet: PacketView, response: &mut PacketBuilder) -> Result<(), Error> {
         let object_id = packet.object_id();
         let method = packet.method();
         let data = packet.data();
 
         if method == DELETE_REQUEST {
-            self.delete(object_id);
-            Vec::new()
+            self.delete(object_id)
+        } else if method == LOOKUP {
+            let name: String =
+                serde_cbor::from_slice(data).map_err(|err| Error::DeserializationFailed(err.to_string()))?;
+            let handle = self.publications.read().get(&name).map(|publication| publication(self.port.read().clone()));
+            serde_cbor::to_writer(response, &handle).map_err(|err| Error::SerializationFailed(err.to_string()))
         } else {
-            let handlers = self.service_objects.read();
-            crate::service::serde_support::port_thread_local::set_port(self.port.read().clone());
-            let result = handlers
-                .get(&object_id)
-                .unwrap_or_else(|| panic!("Fail to find {} from ServiceForwarder", object_id))
-                .dispatch_and_call(method, data);
-            crate::service::serde_support::port_thread_local::remove_port();
-            result
+            // The lock is released before the call, since the service object may export another one.
+            let object =
+                self.service_objects.read().get(&object_id).cloned().ok_or(Error::ObjectNotFound(object_id))?;
+            let _port_guard =
+                crate::service::serde_support::port_thread_local::set_port_guarded(self.port.read().clone());
+            match object {
+                ServiceObject::Sync(object) => object.dispatch_and_call(method, data, response),
+                // The server gives the request to `handle_async` first, which takes it.
+                #[cfg(feature = "async")]
+                ServiceObject::Async(_) => unreachable!("Async service object is called by handle_async"),
+            }
+        }
+    }
+
+    /// The call, if it is for an async service object. It starts when the future is first polled.
+    #[cfg(feature = "async")]
+    pub fn forward_async(&self, request: &Arc<Packet>) -> Option<BoxFuture<Result<PacketBuilder, Error>>> {
+        let packet = request.view();
+        if is_port_request(packet.method()) {
+            return None
+        }
+        let object = match self.service_objects.read().get(&packet.object_id()) {
+            Some(ServiceObject::Async(object)) => Arc::clone(object),
+            _ => return None,
+        };
+        let _port_guard = crate::service::serde_support::port_thread_local::set_port_guarded(self.port.read().clone());
+        Some(object.dispatch_and_call_async(packet.method(), Arc::clone(request)))
+    }
+
+    /// The counterparty has dropped its last handle to the object.
+    fn delete(&self, id: ServiceObjectId) -> Result<(), Error> {
+        let object = self.service_objects.write().remove(&id).ok_or(Error::ObjectNotFound(id))?;
+        self.ids.lock().release(id);
+        // The object is dropped out of the lock, since it may hold remotes to delete.
+        drop(object);
+        Ok(())
+    }
+
+    /// Removes every service object, regardless of the handles to it.
+    /// This is for when the counterparty is gone, so no one can delete them.
+    pub fn release_all(&self) {
+        let service_objects = std::mem::take(&mut *self.service_objects.write());
+        let mut ids = self.ids.lock();
+        for id in service_objects.keys() {
+            ids.release(*id);
+        }
+        drop(ids);
+        // The objects are dropped out of the locks, since they may hold remotes to delete.
+        drop(service_objects);
+    }
+
+    /// Replaces the previous publication of the name, if any.
+    pub fn publish(&self, name: String, publication: Publication) {
+        if self.publications.write().insert(name, publication).is_some() {
+            debug!("A service object is published again with the same name");
         }
     }
 
-    fn delete(&self, id: ServiceObjectId) {
-        self.service_objects.write().remove(&id).unwrap();
-        self.available_ids.write().push_back(id);
+    pub fn unpublish(&self, name: &str) -> bool {
+        self.publications.write().remove(name).is_some()
     }
 
     /// Be careful of this circular reference
@@ -96,7 +237,23 @@ impl Default for ServiceForwarder {
 }
 
 impl Handler for ServiceForwarder {
-    fn handle(&self, input: PacketView) -> Vec<u8> {
-        self.forward_and_call(input)
+    fn handle(&self, input: PacketView, response: &mut PacketBuilder) -> Result<(), Error> {
+        self.forward_and_call(input, response)
+    }
+
+    /// Method ids are unique only within a trait, so the service object tells the name.
+    fn method_name(&self, input: PacketView) -> Option<(&'static str, &'static str)> {
+        self.service_objects.read().get(&input.object_id())?.method_name(input.method())
+    }
+
+    #[cfg(feature = "async")]
+    fn handle_async(&self, request: &Arc<Packet>) -> Option<BoxFuture<Result<PacketBuilder, Error>>> {
+        self.forward_async(request)
+    }
+
+    #[cfg(feature = "async")]
+    fn is_async(&self, input: PacketView) -> bool {
+        !is_port_request(input.method())
+            && matches!(self.service_objects.read().get(&input.object_id()), Some(ServiceObject::Async(_)))
     }
 }
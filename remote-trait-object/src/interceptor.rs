@@ -0,0 +1,107 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Interceptors see every call to a service object, both on the side that makes it and on the side that handles it.
+//! They are given to `ContextBuilder::interceptor`, and are for the logic around the calls,
+//! like auditing, authorization and metrics.
+
+use crate::forwarder::ServiceObjectId;
+use crate::service::MethodId;
+use crate::Error;
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    /// The call is made by this side.
+    Client,
+    /// The call is from the counterparty.
+    Server,
+}
+
+/// A call to a service object, as the interceptors see it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Call {
+    pub side: Side,
+    pub object_id: ServiceObjectId,
+    /// The id that the server side dispatches.
+    pub method: MethodId,
+    /// (trait name, method name) of the method.
+    /// It is None if the server side can't tell it, like for a service object that is not made by the macro.
+    pub name: Option<(&'static str, &'static str)>,
+}
+
+/// The server side runs it before the request is handled, on a server thread,
+/// or on the thread that receives the requests for an async service object with an executor.
+/// A panic in it fails the call, like a panic of the service object.
+pub trait Interceptor: Send + Sync {
+    /// Called with the serialized arguments, before the call is sent or dispatched.
+    /// An error rejects the call, and the caller gets it as the failure of the call.
+    fn before(&self, _call: &Call, _args: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called with the serialized return value or the failure, after the call finishes.
+    /// It is called only if `before` of this interceptor let the call go.
+    fn after(&self, _call: &Call, _result: Result<&[u8], &Error>) {}
+}
+
+impl fmt::Debug for dyn Interceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Interceptor")
+    }
+}
+
+/// A call that the interceptors let go, which they see again when it finishes.
+pub(crate) struct Intercepted {
+    call: Call,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+}
+
+impl Intercepted {
+    /// `after` is called in the reverse order of `before`.
+    pub fn finish(self, result: Result<&[u8], &Error>) {
+        for interceptor in self.interceptors.iter().rev() {
+            interceptor.after(&self.call, result);
+        }
+    }
+}
+
+/// Runs `before` of the interceptors in order. If one of them rejects the call,
+/// the ones that let it go see the rejection in `after`. Returns None if there is no interceptor.
+pub(crate) fn intercept(
+    interceptors: &[Arc<dyn Interceptor>],
+    call: Call,
+    args: &[u8],
+) -> Result<Option<Intercepted>, Error> {
+    if interceptors.is_empty() {
+        return Ok(None)
+    }
+    for (i, interceptor) in interceptors.iter().enumerate() {
+        if let Err(err) = interceptor.before(&call, args) {
+            Intercepted {
+                call,
+                interceptors: interceptors[..i].to_vec(),
+            }
+            .finish(Err(&err));
+            return Err(err)
+        }
+    }
+    Ok(Some(Intercepted {
+        call,
+        interceptors: interceptors.to_vec(),
+    }))
+}
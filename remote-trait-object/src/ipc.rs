@@ -14,11 +14,18 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod intra;
 pub mod multiplex;
+#[cfg(unix)]
+pub mod unix;
 
 pub trait IpcSend: Send {
     /// It might block until counterparty's recv(). Even if not, the order is still guaranteed.
     fn send(&self, data: &[u8]);
+    /// Same as `send`, but takes the message so that an implementation can pass it on without a copy.
+    fn send_owned(&self, data: Vec<u8>) {
+        self.send(&data)
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -14,14 +14,26 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+//! IPC between two contexts in the same process, over crossbeam channels.
+
+use super::{IpcRecv, IpcSend, RecvError, Terminate};
 use crossbeam::channel::{bounded, Receiver, Select, SelectTimeoutError, Sender};
-use remote_trait_object::ipc::*;
+
+/// The number of messages that can be sent without being received.
+const CHANNEL_CAPACITY: usize = 256;
 
 pub struct IntraSend(Sender<Vec<u8>>);
 
 impl IpcSend for IntraSend {
     fn send(&self, data: &[u8]) {
-        self.0.send(data.to_vec()).unwrap();
+        self.send_owned(data.to_vec())
+    }
+
+    fn send_owned(&self, data: Vec<u8>) {
+        if let Err(err) = self.0.send(data) {
+            // The counterparty is gone. Its termination will be noticed by the receiving side.
+            debug!("Failed to send a message in Intra: {}", err);
+        }
     }
 }
 
@@ -67,9 +79,7 @@ impl IpcRecv for IntraRecv {
                 }
             },
             i if i == terminator_index => {
-                let _ = selected_op
-                    .recv(&self.terminator_receiver)
-                    .expect("Terminator should be dropped after this thread");
+                selected_op.recv(&self.terminator_receiver).expect("Terminator should be dropped after this thread");
                 return Err(RecvError::Termination)
             }
             _ => unreachable!(),
@@ -83,17 +93,11 @@ impl IpcRecv for IntraRecv {
     }
 }
 
-pub struct IpcEnds {
-    pub send1: IntraSend,
-    pub recv1: IntraRecv,
-    pub send2: IntraSend,
-    pub recv2: IntraRecv,
-}
-
-pub fn create() -> IpcEnds {
-    let (a_sender, a_receiver) = bounded(256);
+/// Two connected ends.
+pub fn pair() -> ((IntraSend, IntraRecv), (IntraSend, IntraRecv)) {
+    let (a_sender, a_receiver) = bounded(CHANNEL_CAPACITY);
     let (a_termination_sender, a_termination_receiver) = bounded(1);
-    let (b_sender, b_receiver) = bounded(256);
+    let (b_sender, b_receiver) = bounded(CHANNEL_CAPACITY);
     let (b_termination_sender, b_termination_receiver) = bounded(1);
 
     let send1 = IntraSend(b_sender);
@@ -110,10 +114,5 @@ pub fn create() -> IpcEnds {
         terminator: b_termination_sender,
     };
 
-    IpcEnds {
-        recv1,
-        send1,
-        recv2,
-        send2,
-    }
+    ((send1, recv1), (send2, recv2))
 }
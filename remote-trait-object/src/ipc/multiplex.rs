@@ -14,26 +14,76 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::forwarder::{PING, PONG};
 use crate::ipc::{IpcRecv, IpcSend, RecvError, Terminate};
 use crate::{Packet, PacketView};
 use crossbeam::channel::{self, Receiver, Sender};
 use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum ForwardResult {
     Request,
     Response,
+    Handshake,
+    /// The counterparty is shutting down, and it sends nothing after this.
+    Goodbye,
+    Ping,
+    Pong,
 }
 
 pub trait Forward {
     fn forward(data: PacketView) -> ForwardResult;
 }
 
+/// Why the connection to the counterparty ended, other than the shutdown of the multiplexer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Termination {
+    /// The counterparty closed the connection, or said goodbye.
+    Closed,
+    /// The counterparty didn't answer the pings.
+    Unresponsive,
+}
+
+/// Called on the receiver thread when the connection to the counterparty ends,
+/// after the channels of the received packets are closed. It is not called when the multiplexer is shut down.
+pub type OnTermination = Box<dyn FnOnce(Termination) + Send>;
+
+/// When nothing is received from the counterparty for `interval`, it is pinged.
+/// The counterparty is taken as unresponsive when `max_misses` pings in a row are not answered in `interval`.
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub max_misses: u32,
+}
+
+/// What the receiver thread knows about the counterparty.
+#[derive(Debug, Default)]
+pub struct Liveness {
+    missed: AtomicU32,
+    termination: Mutex<Option<Termination>>,
+}
+
+impl Liveness {
+    /// The number of the pings in a row that the counterparty didn't answer.
+    pub fn missed(&self) -> u32 {
+        self.missed.load(Ordering::SeqCst)
+    }
+
+    pub fn termination(&self) -> Option<Termination> {
+        *self.termination.lock()
+    }
+}
+
 pub struct MultiplexResult {
     pub request_recv: Receiver<Packet>,
     pub response_recv: Receiver<Packet>,
+    pub handshake_recv: Receiver<Packet>,
     pub multiplexed_send: Sender<Packet>,
+    pub liveness: Arc<Liveness>,
     pub multiplexer: Multiplexer,
 }
 
@@ -43,25 +93,56 @@ pub struct Multiplexer {
     receiver_terminator: Option<Mutex<Box<dyn Terminate>>>,
     sender_thread: Option<thread::JoinHandle<()>>,
     sender_terminator: Sender<()>,
+    /// Tells the receiver thread that the termination is not from the counterparty.
+    shutting_down: Arc<AtomicBool>,
 }
 
 impl Multiplexer {
-    pub fn multiplex<IpcReceiver, IpcSender, Forwarder>(ipc_send: IpcSender, ipc_recv: IpcReceiver) -> MultiplexResult
+    pub fn multiplex<IpcReceiver, IpcSender, Forwarder>(
+        channel_size: usize,
+        ipc_send: IpcSender,
+        ipc_recv: IpcReceiver,
+        heartbeat: Option<Heartbeat>,
+        on_termination: OnTermination,
+    ) -> MultiplexResult
     where
         IpcReceiver: IpcRecv + 'static,
         IpcSender: IpcSend + 'static,
         Forwarder: Forward, {
-        let (request_send, request_recv) = channel::bounded(1);
-        let (response_send, response_recv) = channel::bounded(1);
+        let (request_send, request_recv) = channel::bounded(channel_size);
+        let (response_send, response_recv) = channel::bounded(channel_size);
+        // The counterparty sends the handshake only once.
+        let (handshake_send, handshake_recv) = channel::bounded(1);
         let receiver_terminator: Option<Mutex<Box<dyn Terminate>>> =
             Some(Mutex::new(Box::new(ipc_recv.create_terminator())));
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let liveness = Arc::new(Liveness::default());
+        let (multiplexed_send, from_multiplexed_send) = channel::bounded(channel_size);
 
+        let shutting_down_ = Arc::clone(&shutting_down);
+        let liveness_ = Arc::clone(&liveness);
+        let pong_send = multiplexed_send.clone();
         let receiver_thread = thread::Builder::new()
             .name("receiver multiplexer".into())
-            .spawn(move || receiver_loop::<Forwarder, IpcReceiver>(ipc_recv, request_send, response_send))
+            .spawn(move || {
+                let termination = receiver_loop::<Forwarder, IpcReceiver>(
+                    ipc_recv,
+                    request_send,
+                    response_send,
+                    handshake_send,
+                    pong_send,
+                    heartbeat,
+                    &liveness_,
+                );
+                if shutting_down_.load(Ordering::SeqCst) {
+                    return
+                }
+                debug!("Connection to the counterparty ended: {:?}", termination);
+                *liveness_.termination.lock() = Some(termination);
+                on_termination(termination);
+            })
             .unwrap();
 
-        let (multiplexed_send, from_multiplexed_send) = channel::bounded(1);
         let (sender_terminator, recv_sender_terminate) = channel::bounded(1);
         let sender_thread = thread::Builder::new()
             .name("sender multiplexer".into())
@@ -71,17 +152,21 @@ impl Multiplexer {
         MultiplexResult {
             request_recv,
             response_recv,
+            handshake_recv,
             multiplexed_send,
+            liveness,
             multiplexer: Multiplexer {
                 receiver_thread: Some(receiver_thread),
                 sender_thread: Some(sender_thread),
                 receiver_terminator,
                 sender_terminator,
+                shutting_down,
             },
         }
     }
 
     pub fn shutdown(mut self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
         self.receiver_terminator.take().unwrap().into_inner().terminate();
         self.receiver_thread.take().unwrap().join().unwrap();
         if let Err(_err) = self.sender_terminator.send(()) {
@@ -91,22 +176,63 @@ impl Multiplexer {
     }
 }
 
+/// The packets of the multiplexer itself are sent with `try_send`, not to block the receiving.
+/// They are dropped only when the channel is full, which means that the packets flow anyway.
+fn send_control(multiplexed_send: &Sender<Packet>, method: crate::service::MethodId) {
+    if multiplexed_send.try_send(Packet::new_request(0, method, &[])).is_err() {
+        debug!("Drop a control packet {} in multiplex", method);
+    }
+}
+
 fn receiver_loop<Forwarder: Forward, Receiver: IpcRecv>(
     ipc_recv: Receiver,
     request_send: Sender<Packet>,
     response_send: Sender<Packet>,
-) {
+    handshake_send: Sender<Packet>,
+    multiplexed_send: Sender<Packet>,
+    heartbeat: Option<Heartbeat>,
+    liveness: &Liveness,
+) -> Termination {
+    // Whether a ping is sent after the last packet from the counterparty
+    let mut pinged = false;
     loop {
-        let message = match ipc_recv.recv(None) {
-            Err(RecvError::TimeOut) => panic!(),
+        let message = match ipc_recv.recv(heartbeat.map(|heartbeat| heartbeat.interval)) {
+            Err(RecvError::TimeOut) => {
+                let heartbeat = match heartbeat {
+                    Some(heartbeat) => heartbeat,
+                    None => {
+                        warn!("ipc_recv timed out, though the heartbeat is disabled");
+                        continue
+                    }
+                };
+                if pinged {
+                    let missed = liveness.missed.fetch_add(1, Ordering::SeqCst) + 1;
+                    if missed >= heartbeat.max_misses {
+                        warn!("Counterparty didn't answer {} pings", missed);
+                        return Termination::Unresponsive
+                    }
+                }
+                send_control(&multiplexed_send, PING);
+                pinged = true;
+                continue
+            }
             Err(RecvError::Termination) => {
                 debug!("ipc_recv is closed in multiplex");
-                return
+                return Termination::Closed
             }
             Ok(data) => data,
         };
+        // Any packet tells that the counterparty is alive.
+        pinged = false;
+        liveness.missed.store(0, Ordering::SeqCst);
 
-        let packet_view = PacketView::new(&message);
+        let packet_view = match PacketView::parse(&message) {
+            Ok(packet_view) => packet_view,
+            Err(err) => {
+                error!("Drop a malformed packet from the counterparty: {}", err);
+                continue
+            }
+        };
         trace!("Receive message in multiplex {}", packet_view);
         let forward_result = Forwarder::forward(packet_view);
         let packet = Packet::new_from_buffer(message);
@@ -115,6 +241,21 @@ fn receiver_loop<Forwarder: Forward, Receiver: IpcRecv>(
             ForwardResult::Request => request_send.send(packet).unwrap(),
 
             ForwardResult::Response => response_send.send(packet).unwrap(),
+
+            ForwardResult::Handshake => {
+                if handshake_send.try_send(packet).is_err() {
+                    error!("Drop a duplicated handshake from the counterparty");
+                }
+            }
+
+            ForwardResult::Goodbye => {
+                debug!("Counterparty said goodbye");
+                return Termination::Closed
+            }
+
+            ForwardResult::Ping => send_control(&multiplexed_send, PONG),
+
+            ForwardResult::Pong => {}
         }
     }
 }
@@ -131,7 +272,10 @@ fn sender_loop(ipc_sender: impl IpcSend, from_multiplexed_send: Receiver<Packet>
             },
             recv(from_terminator) -> msg => match msg {
                 Ok(()) => {
-                    // Received termination flag
+                    // Received termination flag. The packets sent before it still go out, like the goodbye.
+                    while let Ok(data) = from_multiplexed_send.try_recv() {
+                        ipc_sender.send_owned(data.into_vec());
+                    }
                     return;
                 }
                 Err(err) => {
@@ -139,7 +283,7 @@ fn sender_loop(ipc_sender: impl IpcSend, from_multiplexed_send: Receiver<Packet>
                 }
             },
         };
-        ipc_sender.send(&data.into_vec());
+        ipc_sender.send_owned(data.into_vec());
     }
 }
 
@@ -0,0 +1,241 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! IPC over a Unix domain socket.
+//! Each message is framed with its length, which is a little-endian u32.
+
+use super::{IpcRecv, IpcSend, RecvError, Terminate};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A frame longer than this is taken as a broken connection rather than allocated.
+pub const MAX_FRAME_LEN: usize = 1 << 30;
+
+pub struct UnixSend {
+    stream: UnixStream,
+}
+
+impl IpcSend for UnixSend {
+    fn send(&self, data: &[u8]) {
+        if data.len() > MAX_FRAME_LEN {
+            // The counterparty would take it as a broken connection, so it never leaves here.
+            error!("Drop a message of {} bytes, which is too long for the unix socket", data.len());
+            return
+        }
+        let mut stream = &self.stream;
+        let result = stream.write_all(&(data.len() as u32).to_le_bytes()).and_then(|_| stream.write_all(data));
+        if let Err(err) = result {
+            // The counterparty is gone. Its termination will be noticed by the receiving side.
+            debug!("Failed to send a message through the unix socket: {}", err);
+        }
+    }
+}
+
+pub struct UnixRecv {
+    stream: UnixStream,
+    terminated: Arc<AtomicBool>,
+}
+
+pub struct Terminator {
+    stream: UnixStream,
+    terminated: Arc<AtomicBool>,
+}
+
+impl Terminate for Terminator {
+    fn terminate(&self) {
+        self.terminated.store(true, Ordering::SeqCst);
+        // Shutting down the reading side wakes up the blocked read.
+        if let Err(err) = self.stream.shutdown(Shutdown::Read) {
+            debug!("Terminate is called after the socket is closed {}", err);
+        }
+    }
+}
+
+impl UnixRecv {
+    fn read_frame(&self, timeout: Option<Duration>) -> Result<Vec<u8>, RecvError> {
+        let mut stream = &self.stream;
+        // Zero duration is not allowed as a timeout of the socket.
+        let timeout = timeout.map(|timeout| timeout.max(Duration::from_millis(1)));
+        stream.set_read_timeout(timeout).map_err(|err| self.closed(err))?;
+
+        let mut header = [0_u8; 4];
+        let mut read = 0;
+        while read < header.len() {
+            match stream.read(&mut header[read..]) {
+                Ok(0) => return Err(self.closed(ErrorKind::UnexpectedEof.into())),
+                Ok(n) => {
+                    if read == 0 && timeout.is_some() {
+                        // Once a frame started, we read it to the end not to lose the framing.
+                        stream.set_read_timeout(None).map_err(|err| self.closed(err))?;
+                    }
+                    read += n;
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if read == 0 && (err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut) => {
+                    return Err(RecvError::TimeOut)
+                }
+                Err(err) => return Err(self.closed(err)),
+            }
+        }
+
+        let len = u32::from_le_bytes(header) as usize;
+        if len > MAX_FRAME_LEN {
+            error!("Counterparty sent a frame of {} bytes", len);
+            return Err(RecvError::Termination)
+        }
+        let mut data = vec![0_u8; len];
+        stream.read_exact(&mut data).map_err(|err| self.closed(err))?;
+        Ok(data)
+    }
+
+    fn closed(&self, err: io::Error) -> RecvError {
+        if !self.terminated.load(Ordering::SeqCst) {
+            debug!("Counterparty connection is closed in unix socket: {}", err);
+        }
+        RecvError::Termination
+    }
+}
+
+impl IpcRecv for UnixRecv {
+    type Terminator = Terminator;
+
+    fn recv(&self, timeout: Option<Duration>) -> Result<Vec<u8>, RecvError> {
+        if self.terminated.load(Ordering::SeqCst) {
+            return Err(RecvError::Termination)
+        }
+        self.read_frame(timeout)
+    }
+
+    fn create_terminator(&self) -> Self::Terminator {
+        Terminator {
+            stream: self.stream.try_clone().expect("Failed to clone the unix socket"),
+            terminated: Arc::clone(&self.terminated),
+        }
+    }
+}
+
+/// Splits a connected stream into the two ends that a `Context` takes.
+pub fn split(stream: UnixStream) -> io::Result<(UnixSend, UnixRecv)> {
+    let send = UnixSend {
+        stream: stream.try_clone()?,
+    };
+    let recv = UnixRecv {
+        stream,
+        terminated: Arc::new(AtomicBool::new(false)),
+    };
+    Ok((send, recv))
+}
+
+/// Connects to the socket bound at `path`.
+pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<(UnixSend, UnixRecv)> {
+    split(UnixStream::connect(path)?)
+}
+
+/// Accepts a connection from the listener.
+pub fn accept(listener: &UnixListener) -> io::Result<(UnixSend, UnixRecv)> {
+    let (stream, _) = listener.accept()?;
+    split(stream)
+}
+
+/// Binds a socket at `path` and waits for the single counterparty to connect.
+pub fn listen<P: AsRef<Path>>(path: P) -> io::Result<(UnixSend, UnixRecv)> {
+    accept(&UnixListener::bind(path)?)
+}
+
+/// Two connected ends from a socketpair.
+/// One can be given to a child process, which uses `split` on its inherited stream.
+pub fn pair() -> io::Result<((UnixSend, UnixRecv), (UnixSend, UnixRecv))> {
+    let (a, b) = UnixStream::pair()?;
+    Ok((split(a)?, split(b)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn framing() {
+        let ((send1, recv1), (send2, recv2)) = pair().unwrap();
+        let long = vec![7_u8; 1 << 20];
+        // A long message doesn't fit in the socket buffer, so it must be sent from another thread.
+        let sender = {
+            let long = long.clone();
+            thread::spawn(move || {
+                send1.send(b"hello");
+                send1.send(b"");
+                send1.send(&long);
+            })
+        };
+        assert_eq!(recv2.recv(None).unwrap(), b"hello");
+        assert_eq!(recv2.recv(None).unwrap(), b"");
+        assert_eq!(recv2.recv(None).unwrap(), long);
+        sender.join().unwrap();
+
+        send2.send(b"world");
+        assert_eq!(recv1.recv(Some(Duration::from_millis(100))).unwrap(), b"world");
+    }
+
+    #[test]
+    fn timeout() {
+        let ((_send1, recv1), (send2, _recv2)) = pair().unwrap();
+        assert_eq!(recv1.recv(Some(Duration::from_millis(10))), Err(RecvError::TimeOut));
+        send2.send(b"late");
+        assert_eq!(recv1.recv(Some(Duration::from_millis(100))).unwrap(), b"late");
+    }
+
+    #[test]
+    fn terminator_wakes_blocked_recv() {
+        let ((_send1, recv1), _end2) = pair().unwrap();
+        let terminator = recv1.create_terminator();
+        let receiver = thread::spawn(move || recv1.recv(None));
+        thread::sleep(Duration::from_millis(50));
+        terminator.terminate();
+        assert_eq!(receiver.join().unwrap(), Err(RecvError::Termination));
+    }
+
+    #[test]
+    fn counterparty_close() {
+        let ((_send1, recv1), end2) = pair().unwrap();
+        drop(end2);
+        assert_eq!(recv1.recv(None), Err(RecvError::Termination));
+    }
+
+    #[test]
+    fn connect_to_path() {
+        let path = std::env::temp_dir().join(format!("rto-unix-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let client = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let (send, recv) = connect(path).unwrap();
+                send.send(b"ping");
+                recv.recv(None).unwrap()
+            })
+        };
+        let (send, recv) = accept(&listener).unwrap();
+        assert_eq!(recv.recv(None).unwrap(), b"ping");
+        send.send(b"pong");
+        assert_eq!(client.join().unwrap(), b"pong");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -19,8 +19,11 @@ extern crate crossbeam;
 #[macro_use]
 extern crate log;
 
+pub mod codec;
 mod context;
+mod error;
 mod forwarder;
+mod interceptor;
 pub mod ipc;
 mod packet;
 mod port;
@@ -29,15 +32,26 @@ mod service;
 #[cfg(test)]
 mod tests;
 
-pub use context::Context;
-pub use packet::{Packet, PacketView, SlotId};
+pub use codec::{Codec, Format};
+pub use context::{Context, ContextBuilder, Health, ShutdownReport};
+pub use error::Error;
+pub use interceptor::{Call, Interceptor, Side};
+pub use packet::{Packet, PacketBuilder, PacketError, PacketView, SlotId, PROTOCOL_VERSION};
+#[cfg(feature = "async")]
+pub use port::server::Executor;
 pub use port::Port;
-pub use service::id::setup_identifiers;
+#[cfg(feature = "async")]
+pub use service::async_dispatch::{AsyncDispatch, BoxFuture, ExportAsyncService};
+#[cfg(feature = "id-map-file")]
+pub use service::id::IdMapError;
+pub use service::id::{setup_identifiers, IdMap, IdMapDiff};
 pub use service::{
     serde_support::SArc, Dispatch, ExportService, Handle, HandleToExchange, ImportService, MethodId, Service,
 };
 
 pub mod macro_env {
     pub use super::*;
-    pub use service::id::{IdMap, MethodIdAtomic, ID_ORDERING, MID_REG};
+    #[cfg(feature = "async")]
+    pub use service::async_dispatch::serve;
+    pub use service::id::{hash_method_id, IdMap, MethodIdAtomic, ID_ORDERING, MID_REG};
 }
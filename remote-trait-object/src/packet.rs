@@ -14,16 +14,21 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::forwarder::ServiceObjectId;
+use crate::forwarder::{is_port_request, ServiceObjectId, RESERVED_METHOD_ID_START};
 use crate::service::MethodId;
 use std::fmt;
+use std::io;
 
-#[derive(Debug, Copy, Clone)]
+/// Identifies an outstanding call.
+///
+/// Lower 16 bits are the index of the call slot, and upper 16 bits are the generation of the slot,
+/// which changes every time the slot is reused. So a late response to an old call never matches a new one.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct SlotId(u32);
 
 impl fmt::Display for SlotId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "SlotId {{ id: {:?}, type: {:?} }}", self.0, self.get_type())
+        write!(f, "SlotId {{ index: {}, generation: {} }}", self.index(), self.generation())
     }
 }
 
@@ -34,39 +39,38 @@ pub enum SlotType {
 }
 
 impl SlotId {
+    /// Generation 0 is never used by a call, so this doesn't match any call.
     pub fn empty() -> Self {
-        Self(999)
+        Self(0)
     }
 
-    pub fn new(num: u32) -> Self {
-        Self(num)
+    pub fn new(index: u16, generation: u16) -> Self {
+        Self(u32::from(generation) << 16 | u32::from(index))
     }
 
-    // FIXME: Please use unique slot id
-    pub fn new_request() -> Self {
-        Self(1001)
+    pub fn from_raw(raw: u32) -> Self {
+        Self(raw)
     }
 
-    pub fn get_type(&self) -> SlotType {
-        if self.0 >= SLOT_CALL_OR_RETURN_INDICATOR.0 {
-            SlotType::Request
-        } else {
-            SlotType::Response
-        }
+    pub fn index(&self) -> u16 {
+        self.0 as u16
     }
 
-    pub fn change_to_response(&mut self) {
-        assert!(self.0 >= SLOT_CALL_OR_RETURN_INDICATOR.0);
-        self.0 -= SLOT_CALL_OR_RETURN_INDICATOR.0;
+    pub fn generation(&self) -> u16 {
+        (self.0 >> 16) as u16
     }
 
-    pub fn into_request(self) -> Self {
-        assert!(self.0 < SLOT_CALL_OR_RETURN_INDICATOR.0);
-        Self(self.0 + SLOT_CALL_OR_RETURN_INDICATOR.0)
+    /// Same slot for the next call.
+    pub fn next_generation(self) -> Self {
+        let generation = match self.generation().wrapping_add(1) {
+            0 => 1,
+            generation => generation,
+        };
+        Self::new(self.index(), generation)
     }
 
     pub fn as_usize(&self) -> usize {
-        self.0 as usize
+        self.index() as usize
     }
 
     pub fn as_raw(&self) -> u32 {
@@ -74,20 +78,39 @@ impl SlotId {
     }
 }
 
-const SLOT_CALL_OR_RETURN_INDICATOR: SlotId = SlotId(1000);
-
-// FIXME: repr(C) is not a reliable encoding method.
-// We need to fix the endianness of binary data.
-#[repr(C)]
+/// The packet is a response to the call of the same slot.
+const FLAG_RESPONSE: u8 = 1 << 1;
+/// The response carries an encoded `Error` instead of the return value.
+const FLAG_ERROR: u8 = 1 << 0;
+/// The request expects no response, so it has no slot either.
+const FLAG_ONEWAY: u8 = 1 << 2;
+const KNOWN_FLAGS: u8 = FLAG_ERROR | FLAG_RESPONSE | FLAG_ONEWAY;
+
+/// Every packet starts with this, so that garbage from a wrong peer is not taken as a packet.
+const MAGIC: [u8; 2] = *b"RT";
+/// Bump this whenever the layout or the meaning of the header changes.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Header is encoded explicitly, so that the peers built for different architectures can talk.
+///
+/// | offset | size | field                     |
+/// |--------|------|---------------------------|
+/// | 0      | 2    | magic (`"RT"`)            |
+/// | 2      | 1    | protocol version          |
+/// | 3      | 1    | flags                     |
+/// | 4      | 4    | slot (little endian)      |
+/// | 8      | 8    | object id (little endian) |
+/// | 16     | 4    | method (little endian)    |
 struct PacketHeader {
     pub slot: SlotId,
     pub service_object_id: ServiceObjectId,
     pub method: MethodId,
+    pub flags: u8,
 }
 
 impl PacketHeader {
-    pub fn len() -> usize {
-        std::mem::size_of::<PacketHeader>()
+    pub const fn len() -> usize {
+        20
     }
 
     pub fn new(slot: SlotId, service_object_id: ServiceObjectId, method: MethodId) -> Self {
@@ -95,20 +118,89 @@ impl PacketHeader {
             slot,
             service_object_id,
             method,
+            flags: 0,
         }
     }
 
+    /// Checks whether the buffer is written by a compatible peer.
+    pub fn check(buffer: &[u8]) -> Result<(), PacketError> {
+        if buffer[0..2] != MAGIC {
+            return Err(PacketError::InvalidMagic)
+        }
+        if buffer[2] != PROTOCOL_VERSION {
+            return Err(PacketError::VersionMismatch {
+                expected: PROTOCOL_VERSION,
+                found: buffer[2],
+            })
+        }
+        Ok(())
+    }
+
     pub fn from_buffer(buffer: &[u8]) -> Self {
-        unsafe { std::ptr::read(buffer.as_ptr().cast()) }
+        PacketHeader {
+            flags: buffer[3],
+            slot: SlotId::from_raw(read_u32(&buffer[4..8])),
+            service_object_id: read_u64(&buffer[8..16]),
+            method: read_u32(&buffer[16..20]),
+        }
     }
 
     pub fn write(&self, buffer: &mut [u8]) {
-        unsafe {
-            std::ptr::copy_nonoverlapping(self, buffer.as_mut_ptr().cast(), 1);
+        buffer[0..2].copy_from_slice(&MAGIC);
+        buffer[2] = PROTOCOL_VERSION;
+        buffer[3] = self.flags;
+        buffer[4..8].copy_from_slice(&self.slot.as_raw().to_le_bytes());
+        buffer[8..16].copy_from_slice(&self.service_object_id.to_le_bytes());
+        buffer[16..20].copy_from_slice(&self.method.to_le_bytes());
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0_u8; 4];
+    buf.copy_from_slice(bytes);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0_u8; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+/// Reason why a received buffer can't be taken as a packet.
+#[derive(Debug, PartialEq)]
+pub enum PacketError {
+    TooShort {
+        len: usize,
+    },
+    InvalidMagic,
+    VersionMismatch {
+        expected: u8,
+        found: u8,
+    },
+    UnknownFlags(u8),
+    ReservedMethod(MethodId),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::TooShort {
+                len,
+            } => write!(f, "Packet of {} bytes is shorter than the header", len),
+            PacketError::InvalidMagic => write!(f, "Invalid magic number"),
+            PacketError::VersionMismatch {
+                expected,
+                found,
+            } => write!(f, "Protocol version mismatch: expected {}, found {}", expected, found),
+            PacketError::UnknownFlags(flags) => write!(f, "Unknown flags {:#x}", flags),
+            PacketError::ReservedMethod(method) => write!(f, "Method {} is reserved", method),
         }
     }
 }
 
+impl std::error::Error for PacketError {}
+
 #[derive(Debug)]
 pub struct PacketView<'a> {
     buffer: &'a [u8],
@@ -116,17 +208,45 @@ pub struct PacketView<'a> {
 
 impl<'a> fmt::Display for PacketView<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Packet {{ slot: {}, object id: {}, method: {} }}", self.slot(), self.object_id(), self.method())
+        write!(
+            f,
+            "Packet {{ slot: {}, type: {:?}, object id: {}, method: {} }}",
+            self.slot(),
+            self.slot_type(),
+            self.object_id(),
+            self.method()
+        )
     }
 }
 
 impl<'a> PacketView<'a> {
+    /// The buffer must be a valid packet. Use `parse` for a buffer from the counterparty.
     pub fn new(buffer: &'a [u8]) -> Self {
         Self {
             buffer,
         }
     }
 
+    /// Validates the buffer, so that a malformed packet from the counterparty can't crash this side.
+    pub fn parse(buffer: &'a [u8]) -> Result<Self, PacketError> {
+        if buffer.len() < PacketHeader::len() {
+            return Err(PacketError::TooShort {
+                len: buffer.len(),
+            })
+        }
+        PacketHeader::check(buffer)?;
+        let header = PacketHeader::from_buffer(buffer);
+        if header.flags & !KNOWN_FLAGS != 0 {
+            return Err(PacketError::UnknownFlags(header.flags))
+        }
+        if header.method >= RESERVED_METHOD_ID_START && !is_port_request(header.method) {
+            return Err(PacketError::ReservedMethod(header.method))
+        }
+        Ok(Self {
+            buffer,
+        })
+    }
+
     pub fn header(&self) -> &'a [u8] {
         &self.buffer[0..PacketHeader::len()]
     }
@@ -140,6 +260,14 @@ impl<'a> PacketView<'a> {
         header.slot
     }
 
+    pub fn slot_type(&self) -> SlotType {
+        if PacketHeader::from_buffer(self.buffer).flags & FLAG_RESPONSE != 0 {
+            SlotType::Response
+        } else {
+            SlotType::Request
+        }
+    }
+
     pub fn object_id(&self) -> ServiceObjectId {
         PacketHeader::from_buffer(self.buffer).service_object_id
     }
@@ -149,6 +277,15 @@ impl<'a> PacketView<'a> {
         header.method
     }
 
+    /// Whether this is an error reply rather than a normal reply.
+    pub fn is_error(&self) -> bool {
+        PacketHeader::from_buffer(self.buffer).flags & FLAG_ERROR != 0
+    }
+
+    pub fn is_oneway(&self) -> bool {
+        PacketHeader::from_buffer(self.buffer).flags & FLAG_ONEWAY != 0
+    }
+
     pub fn to_vec(&self) -> Vec<u8> {
         self.buffer.to_vec()
     }
@@ -177,34 +314,30 @@ impl Packet {
         }
     }
 
+    /// Response without data
     pub fn new_response_from_request(request: PacketView) -> Self {
-        let buffer = request.header().to_vec();
-        let mut packet = Self {
-            buffer,
-        };
-
-        let mut header = packet.header();
-        header.slot.change_to_response();
-        header.write(&mut packet.buffer);
+        PacketBuilder::new().into_response(request)
+    }
 
-        packet
+    /// Error reply. `error` is the encoded `Error` that the caller will receive.
+    pub fn new_error_response_from_request(request: PacketView, error: &[u8]) -> Self {
+        let mut builder = PacketBuilder::with_capacity(error.len());
+        builder.buffer.extend_from_slice(error);
+        builder.into_error_response(request)
     }
 
+    /// Copies `args` into the packet. Use `PacketBuilder` to serialize the arguments right into the packet.
     pub fn new_request(service_object_id: ServiceObjectId, method: MethodId, args: &[u8]) -> Self {
-        let mut buffer = vec![0 as u8; PacketHeader::len() + args.len()];
-        let header = PacketHeader::new(SlotId::new_request(), service_object_id, method);
-        header.write(&mut buffer);
-        buffer[PacketHeader::len()..].copy_from_slice(args);
-        Self {
-            buffer,
-        }
+        let mut builder = PacketBuilder::with_capacity(args.len());
+        builder.buffer.extend_from_slice(args);
+        builder.into_request(service_object_id, method)
     }
 
     pub fn buffer(&self) -> &[u8] {
         &self.buffer
     }
 
-    pub fn view(&self) -> PacketView {
+    pub fn view(&self) -> PacketView<'_> {
         PacketView::new(&self.buffer)
     }
 
@@ -216,18 +349,205 @@ impl Packet {
         self.view().data()
     }
 
-    // FIXME: Use Cursor to reduce data copy
-    pub fn append_data(&mut self, data: &[u8]) {
-        self.buffer.extend_from_slice(data);
-    }
-
     pub fn set_slot(&mut self, slot_id: SlotId) {
         let mut header = self.header();
         header.slot = slot_id;
         header.write(&mut self.buffer);
     }
 
+    pub fn set_oneway(&mut self) {
+        let mut header = self.header();
+        header.flags |= FLAG_ONEWAY;
+        header.write(&mut self.buffer);
+    }
+
     pub fn into_vec(self) -> Vec<u8> {
         self.buffer
     }
 }
+
+/// Writes the data of a packet in place.
+/// The header is reserved at the front of the buffer, so that the data doesn't move when the packet is finished.
+#[derive(Debug)]
+pub struct PacketBuilder {
+    buffer: Vec<u8>,
+}
+
+impl Default for PacketBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PacketBuilder {
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Room for `data_capacity` bytes of data, in addition to the header.
+    pub fn with_capacity(data_capacity: usize) -> Self {
+        let mut buffer = Vec::with_capacity(PacketHeader::len() + data_capacity);
+        buffer.resize(PacketHeader::len(), 0);
+        Self {
+            buffer,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[PacketHeader::len()..]
+    }
+
+    /// Client will assign a slot
+    pub fn into_request(self, service_object_id: ServiceObjectId, method: MethodId) -> Packet {
+        self.finish(PacketHeader::new(SlotId::empty(), service_object_id, method))
+    }
+
+    pub fn into_response(self, request: PacketView) -> Packet {
+        let mut header = PacketHeader::from_buffer(request.buffer);
+        header.flags |= FLAG_RESPONSE;
+        self.finish(header)
+    }
+
+    /// Error reply. The data must be the encoded `Error` that the caller will receive.
+    pub fn into_error_response(self, request: PacketView) -> Packet {
+        let mut header = PacketHeader::from_buffer(request.buffer);
+        header.flags |= FLAG_RESPONSE | FLAG_ERROR;
+        self.finish(header)
+    }
+
+    fn finish(mut self, header: PacketHeader) -> Packet {
+        header.write(&mut self.buffer);
+        Packet {
+            buffer: self.buffer,
+        }
+    }
+}
+
+impl io::Write for PacketBuilder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_is_little_endian() {
+        let packet = Packet::new_request(0x090a_0b0c_0102_0304, 0x0506_0708, &[0xff]);
+        let buffer = packet.buffer();
+        assert_eq!(&buffer[0..4], &[b'R', b'T', PROTOCOL_VERSION, 0]);
+        assert_eq!(&buffer[4..8], &[0, 0, 0, 0]);
+        assert_eq!(&buffer[8..16], &[0x04, 0x03, 0x02, 0x01, 0x0c, 0x0b, 0x0a, 0x09]);
+        assert_eq!(&buffer[16..20], &[0x08, 0x07, 0x06, 0x05]);
+        assert_eq!(&buffer[20..], &[0xff]);
+
+        let view = PacketView::parse(packet.buffer()).unwrap();
+        assert_eq!(view.object_id(), 0x090a_0b0c_0102_0304);
+        assert_eq!(view.method(), 0x0506_0708);
+        assert_eq!(view.data(), &[0xff]);
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut buffer = Packet::new_request(1, 2, &[]).into_vec();
+        buffer[2] = PROTOCOL_VERSION + 1;
+        assert_eq!(PacketView::parse(&buffer).unwrap_err(), PacketError::VersionMismatch {
+            expected: PROTOCOL_VERSION,
+            found: PROTOCOL_VERSION + 1
+        });
+        buffer[0] = 0;
+        assert_eq!(PacketView::parse(&buffer).unwrap_err(), PacketError::InvalidMagic);
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let buffer = Packet::new_request(1, 2, &[]).into_vec();
+        assert_eq!(PacketView::parse(&buffer[..PacketHeader::len() - 1]).unwrap_err(), PacketError::TooShort {
+            len: PacketHeader::len() - 1
+        });
+
+        let mut buffer = Packet::new_request(1, 2, &[]).into_vec();
+        buffer[3] = 1 << 7;
+        assert_eq!(PacketView::parse(&buffer).unwrap_err(), PacketError::UnknownFlags(1 << 7));
+
+        let packet = Packet::new_request(1, RESERVED_METHOD_ID_START, &[]);
+        assert_eq!(
+            PacketView::parse(packet.buffer()).unwrap_err(),
+            PacketError::ReservedMethod(RESERVED_METHOD_ID_START)
+        );
+        let packet = Packet::new_request(1, crate::forwarder::DELETE_REQUEST, &[]);
+        assert!(PacketView::parse(packet.buffer()).is_ok());
+    }
+
+    #[test]
+    fn response_keeps_slot() {
+        let mut request = Packet::new_request(1, 2, &[]);
+        let slot = SlotId::new(3, 0).next_generation();
+        request.set_slot(slot);
+        assert!(matches!(request.view().slot_type(), SlotType::Request));
+
+        let response = Packet::new_response_from_request(request.view());
+        assert!(matches!(response.view().slot_type(), SlotType::Response));
+        assert_eq!(response.view().slot(), slot);
+        assert_eq!(slot.index(), 3);
+        assert_eq!(slot.generation(), 1);
+        assert_ne!(slot.next_generation(), slot);
+        assert_ne!(slot, SlotId::empty());
+    }
+
+    #[test]
+    fn oneway_request() {
+        let mut request = Packet::new_request(1, 2, &[3]);
+        assert!(!request.view().is_oneway());
+        request.set_oneway();
+        let view = PacketView::parse(request.buffer()).unwrap();
+        assert!(view.is_oneway());
+        assert_eq!(view.slot(), SlotId::empty());
+        assert_eq!(view.data(), &[3]);
+    }
+
+    #[test]
+    fn build_in_place() {
+        use std::io::Write;
+
+        let mut builder = PacketBuilder::new();
+        builder.write_all(&[1, 2]).unwrap();
+        builder.write_all(&[3]).unwrap();
+        assert_eq!(builder.data(), &[1, 2, 3]);
+        let data_address = builder.data().as_ptr();
+        let mut request = builder.into_request(4, 5);
+        assert_eq!(request.data().as_ptr(), data_address);
+        request.set_slot(SlotId::new(6, 1));
+
+        let view = PacketView::parse(request.buffer()).unwrap();
+        assert_eq!(view.object_id(), 4);
+        assert_eq!(view.method(), 5);
+        assert_eq!(view.data(), &[1, 2, 3]);
+
+        let mut builder = PacketBuilder::new();
+        builder.write_all(&[7]).unwrap();
+        let response = builder.into_error_response(view);
+        assert!(response.view().is_error());
+        assert!(matches!(response.view().slot_type(), SlotType::Response));
+        assert_eq!(response.view().slot(), SlotId::new(6, 1));
+        assert_eq!(response.data(), &[7]);
+    }
+
+    #[test]
+    fn generation_skips_zero() {
+        let slot = SlotId::new(7, u16::MAX);
+        assert_eq!(slot.next_generation(), SlotId::new(7, 1));
+    }
+}
@@ -15,24 +15,83 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 pub mod client;
+pub mod handshake;
 pub mod server;
 pub mod types;
 
 pub use self::types::Handler;
+use crate::codec::Format;
 use crate::forwarder::ServiceForwarder;
 use crate::forwarder::{ServiceObjectId, DELETE_REQUEST};
-use crate::packet::{Packet, PacketView};
+use crate::interceptor::Interceptor;
+use crate::packet::Packet;
+use crate::service::id::IdMap;
 use crate::service::*;
+use crate::Error;
 use client::Client;
+use handshake::PeerMethods;
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc, Weak,
 };
+use std::time::{Duration, Instant};
+#[cfg(feature = "async")]
+use std::{future::Future, pin::Pin};
+
+/// Response of `Port::call_async`
+#[cfg(feature = "async")]
+pub type ResponseFuture = Pin<Box<dyn Future<Output = Result<Packet, Error>> + Send>>;
 
 pub trait Port: std::fmt::Debug + Send + Sync + 'static {
-    fn call(&self, packet: PacketView) -> Packet;
+    /// If `timeout` is None, the default timeout of the port is used.
+    /// The request is taken by value, so that the port can send it without a copy.
+    fn call(&self, packet: Packet, timeout: Option<Duration>) -> Result<Packet, Error>;
+    /// Sends the request without waiting for the response.
+    /// A port that can't do so may make a round trip instead.
+    fn call_oneway(&self, packet: Packet) -> Result<(), Error> {
+        self.call(packet, None).map(|_| ())
+    }
+    /// Makes the call without blocking the thread. Timeouts don't apply to it.
+    /// A port that can't do so makes the blocking call before returning the future.
+    #[cfg(feature = "async")]
+    fn call_async(&self, packet: Packet) -> ResponseFuture {
+        Box::pin(std::future::ready(self.call(packet, None)))
+    }
+    /// Counts another handle to the service object of the counterparty, which `delete_request` takes away.
+    /// It never makes a call, so that a handle can be imported or cloned anywhere.
+    fn add_ref(&self, id: ServiceObjectId);
     fn delete_request(&self, id: ServiceObjectId);
     fn register(&self, service_object: Arc<dyn Dispatch>) -> HandleToExchange;
+    #[cfg(feature = "async")]
+    fn register_async(&self, _service_object: Arc<dyn async_dispatch::AsyncDispatch>) -> HandleToExchange {
+        panic!("This port can't export an async service object")
+    }
+    /// The id that the counterparty dispatches for the method.
+    /// By default it is the id of this side, which is right when both sides are built with the same ids.
+    fn method_id(&self, trait_name: &str, method_name: &str) -> Result<MethodId, Error> {
+        id::default_method_id(trait_name, method_name).ok_or_else(|| Error::Incompatible {
+            trait_name: trait_name.to_owned(),
+            method_name: method_name.to_owned(),
+        })
+    }
+    /// The id that this side dispatches for the method. Dispatchers get their ids from this on export.
+    fn local_method_id(&self, trait_name: &str, method_name: &str) -> MethodId {
+        id::default_method_id(trait_name, method_name).expect("Method of a service trait is always registered")
+    }
+    /// The format that the service objects of this side take, unless their trait specifies it.
+    fn format(&self) -> Format {
+        Format::Cbor
+    }
+    /// The format that the service objects of the counterparty take, unless their trait specifies it.
+    fn peer_format(&self) -> Result<Format, Error> {
+        Ok(self.format())
+    }
+    /// The interceptors of the calls that the handles make through this port.
+    fn interceptors(&self) -> &[Arc<dyn Interceptor>] {
+        &[]
+    }
 }
 
 /// Weak::new() is not implemented for ?Sized.
@@ -44,8 +103,14 @@ pub fn null_weak_port() -> Weak<dyn Port> {
 #[derive(Debug)]
 pub struct BasicPort {
     registry: Arc<ServiceForwarder>,
-    /// client is None only in the drop function.
-    client: Option<Client>,
+    client: Client,
+    peer_methods: PeerMethods,
+    id_map: IdMap,
+    format: Format,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    /// The number of the handles to each service object of the counterparty.
+    /// The counterparty keeps the object until the delete request, which is sent with the last handle.
+    imported: Mutex<HashMap<ServiceObjectId, usize>>,
     /// If this is on, the port will not request delete
     /// This is useful when the port-port connection is terminating and you don't really
     /// care about the garabage collection.
@@ -53,28 +118,91 @@ pub struct BasicPort {
 }
 
 impl Port for BasicPort {
-    fn call(&self, packet: PacketView) -> Packet {
-        self.client.as_ref().unwrap().call(packet)
+    fn call(&self, packet: Packet, timeout: Option<Duration>) -> Result<Packet, Error> {
+        self.client.call(packet, timeout)
+    }
+
+    fn call_oneway(&self, packet: Packet) -> Result<(), Error> {
+        self.client.send_oneway(packet)
+    }
+
+    #[cfg(feature = "async")]
+    fn call_async(&self, packet: Packet) -> ResponseFuture {
+        Box::pin(self.client.call_async(packet))
+    }
+
+    fn add_ref(&self, id: ServiceObjectId) {
+        *self.imported.lock().entry(id).or_insert(0) += 1;
     }
 
     fn delete_request(&self, id: ServiceObjectId) {
+        {
+            let mut imported = self.imported.lock();
+            match imported.get_mut(&id) {
+                Some(refs) if *refs > 1 => {
+                    *refs -= 1;
+                    return
+                }
+                _ => imported.remove(&id),
+            };
+        }
         if self.no_drop.load(Ordering::SeqCst) {
             return
         }
         let packet = Packet::new_request(id, DELETE_REQUEST, &[]);
-        assert_eq!(self.client.as_ref().unwrap().call(packet.view()).data(), []);
+        match self.client.call(packet, None) {
+            Ok(response) => assert_eq!(response.data(), [0_u8; 0]),
+            Err(err) => debug!("Failed to request delete of {}: {}", id, err),
+        }
     }
 
     fn register(&self, service_object: Arc<dyn Dispatch>) -> HandleToExchange {
         HandleToExchange(self.registry.register_service_object(service_object))
     }
+
+    #[cfg(feature = "async")]
+    fn register_async(&self, service_object: Arc<dyn async_dispatch::AsyncDispatch>) -> HandleToExchange {
+        HandleToExchange(self.registry.register_async_service_object(service_object))
+    }
+
+    fn method_id(&self, trait_name: &str, method_name: &str) -> Result<MethodId, Error> {
+        self.peer_methods.method_id(trait_name, method_name)
+    }
+
+    fn local_method_id(&self, trait_name: &str, method_name: &str) -> MethodId {
+        self.id_map.method_id(trait_name, method_name).expect("Method of a service trait is always registered")
+    }
+
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn peer_format(&self) -> Result<Format, Error> {
+        self.peer_methods.format()
+    }
+
+    fn interceptors(&self) -> &[Arc<dyn Interceptor>] {
+        &self.interceptors
+    }
 }
 
 impl BasicPort {
-    pub fn new(client: Client) -> Arc<Self> {
+    pub fn new(
+        client: Client,
+        registry: Arc<ServiceForwarder>,
+        peer_methods: PeerMethods,
+        id_map: IdMap,
+        format: Format,
+        interceptors: Vec<Arc<dyn Interceptor>>,
+    ) -> Arc<Self> {
         let arc = Arc::new(Self {
-            registry: Arc::new(ServiceForwarder::new()),
-            client: Some(client),
+            registry,
+            client,
+            peer_methods,
+            id_map,
+            format,
+            interceptors,
+            imported: Mutex::new(HashMap::new()),
             no_drop: AtomicBool::new(false),
         });
         let arc2 = arc.clone() as Arc<dyn Port>;
@@ -82,22 +210,26 @@ impl BasicPort {
         arc
     }
 
+    /// Waits for the handshake packet of the counterparty.
+    pub fn handshake(&self) -> Result<(), Error> {
+        self.peer_methods.handshake()
+    }
+
     pub fn get_registry(&self) -> Arc<ServiceForwarder> {
         self.registry.clone()
     }
 
+    /// Fails the calls to the counterparty, and says goodbye. Returns the number of the failed calls.
+    pub fn close(&self) -> usize {
+        self.client.close()
+    }
+
     /// Please call shutdown after Multiplexer::shutdown
-    pub fn shutdown(mut self) {
-        self.client.take().unwrap().shutdown();
+    pub fn shutdown(&self, deadline: Instant) -> bool {
+        self.client.shutdown(deadline)
     }
 
     pub fn set_no_drop(&self) {
         self.no_drop.store(true, Ordering::SeqCst);
     }
 }
-
-impl Drop for BasicPort {
-    fn drop(&mut self) {
-        assert!(self.client.is_none(), "Please call shutdown");
-    }
-}
@@ -14,116 +14,430 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::packet::{Packet, PacketView, SlotId};
-use crate::queue::Queue;
-use crossbeam::channel::RecvTimeoutError::{Disconnected, Timeout};
+use crate::context::Config;
+use crate::forwarder::GOODBYE;
+use crate::packet::{Packet, SlotId};
+use crate::Error;
 use crossbeam::channel::{bounded, Receiver, RecvError, Sender};
+use parking_lot::{Condvar, Mutex};
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::thread;
 use std::time;
+#[cfg(feature = "async")]
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
 
-#[cfg(debug_assertions)]
-const TIMEOUT: std::time::Duration = std::time::Duration::from_millis(1_000_000);
-#[cfg(not(debug_assertions))]
-const TIMEOUT: std::time::Duration = std::time::Duration::from_millis(50);
+/// SlotId has 16 bits for the index.
+pub const MAX_CALL_SLOTS: usize = 1 << 16;
 
-// FIXME: read from config
-const CALLSLOT_SIZE: u32 = 100;
+/// Where the receive loop puts the response of a call, and wakes up its caller.
+#[derive(Debug, Default)]
+struct SlotCell {
+    state: Mutex<SlotState>,
+    arrived: Condvar,
+}
+
+#[derive(Debug)]
+struct SlotState {
+    /// Raw id of the call that the slot is waiting for. Responses to other calls are stale.
+    awaiting: u32,
+    response: Option<Packet>,
+    #[cfg(feature = "async")]
+    waker: Option<Waker>,
+}
+
+impl Default for SlotState {
+    fn default() -> Self {
+        Self {
+            awaiting: SlotId::empty().as_raw(),
+            response: None,
+            #[cfg(feature = "async")]
+            waker: None,
+        }
+    }
+}
 
-/// CallSlot represents an instance of call to the another module
 #[derive(Debug)]
-struct CallSlot {
-    id: SlotId,
-    response: Receiver<Packet>,
+struct FreeSlots {
+    ids: VecDeque<SlotId>,
+    /// Async calls waiting for a free slot, in the order they came
+    #[cfg(feature = "async")]
+    wakers: VecDeque<Waker>,
+}
+
+/// Call slots shared by the callers and the receive loop.
+#[derive(Debug)]
+struct Slots {
+    free: Mutex<FreeSlots>,
+    freed: Condvar,
+    cells: Vec<SlotCell>,
+    /// Why no more response will come. The receive loop has exited, or the client is closed.
+    closed: Mutex<Option<Error>>,
+}
+
+impl Slots {
+    fn new(size: usize) -> Self {
+        Self {
+            free: Mutex::new(FreeSlots {
+                ids: (0..size).map(|i| SlotId::new(i as u16, 0)).collect(),
+                #[cfg(feature = "async")]
+                wakers: VecDeque::new(),
+            }),
+            freed: Condvar::new(),
+            cells: (0..size).map(|_| Default::default()).collect(),
+            closed: Mutex::new(None),
+        }
+    }
+
+    /// Waits for a free slot until the deadline of the call. None means no deadline.
+    fn acquire(&self, deadline: Option<time::Instant>) -> Result<SlotId, Error> {
+        let mut free = self.free.lock();
+        loop {
+            if let Some(error) = self.closed() {
+                return Err(error)
+            }
+            if let Some(id) = free.ids.pop_front() {
+                return Ok(self.start(id))
+            }
+            match deadline {
+                Some(deadline) => {
+                    if self.freed.wait_until(&mut free, deadline).timed_out() && free.ids.is_empty() {
+                        return Err(Error::Timeout)
+                    }
+                }
+                None => self.freed.wait(&mut free),
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    fn try_acquire(&self, waker: &Waker) -> Option<SlotId> {
+        let mut free = self.free.lock();
+        match free.ids.pop_front() {
+            Some(id) => {
+                // It may have been polled without a wake, while it was still waiting.
+                free.wakers.retain(|queued| !queued.will_wake(waker));
+                Some(self.start(id))
+            }
+            None => {
+                // A future that is polled again before a slot is freed waits only once.
+                if !free.wakers.iter().any(|queued| queued.will_wake(waker)) {
+                    free.wakers.push_back(waker.clone());
+                }
+                None
+            }
+        }
+    }
+
+    /// For a future that waited for a slot, and is dropped without one.
+    /// If it was woken for a free slot, the next one in the queue gets the wake instead.
+    #[cfg(feature = "async")]
+    fn stop_waiting(&self, waker: &Waker) {
+        let mut free = self.free.lock();
+        free.wakers.retain(|queued| !queued.will_wake(waker));
+        if !free.ids.is_empty() {
+            if let Some(next) = free.wakers.pop_front() {
+                next.wake();
+            }
+        }
+    }
+
+    fn start(&self, id: SlotId) -> SlotId {
+        let id = id.next_generation();
+        self.cells[id.as_usize()].state.lock().awaiting = id.as_raw();
+        id
+    }
+
+    /// From now on, the receive loop drops the late response to the call.
+    fn release(&self, id: SlotId) {
+        *self.cells[id.as_usize()].state.lock() = Default::default();
+        let mut free = self.free.lock();
+        free.ids.push_back(id);
+        self.freed.notify_one();
+        #[cfg(feature = "async")]
+        if let Some(waker) = free.wakers.pop_front() {
+            waker.wake();
+        }
+    }
+
+    fn wait(&self, id: SlotId, deadline: Option<time::Instant>) -> Result<Packet, Error> {
+        let cell = &self.cells[id.as_usize()];
+        let mut state = cell.state.lock();
+        loop {
+            if let Some(response) = state.response.take() {
+                return Ok(response)
+            }
+            if let Some(error) = self.closed() {
+                return Err(error)
+            }
+            match deadline {
+                Some(deadline) => {
+                    if cell.arrived.wait_until(&mut state, deadline).timed_out() && state.response.is_none() {
+                        return Err(Error::Timeout)
+                    }
+                }
+                None => cell.arrived.wait(&mut state),
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    fn poll_response(&self, id: SlotId, waker: &Waker) -> Poll<Result<Packet, Error>> {
+        let mut state = self.cells[id.as_usize()].state.lock();
+        if let Some(response) = state.response.take() {
+            return Poll::Ready(Ok(response))
+        }
+        if let Some(error) = self.closed() {
+            return Poll::Ready(Err(error))
+        }
+        state.waker = Some(waker.clone());
+        Poll::Pending
+    }
+
+    fn deliver(&self, packet: Packet) {
+        let slot_id = packet.view().slot();
+        let cell = match self.cells.get(slot_id.as_usize()) {
+            Some(cell) => cell,
+            None => {
+                error!("Drop a response to an unknown slot {}", slot_id);
+                return
+            }
+        };
+        let mut state = cell.state.lock();
+        if state.awaiting != slot_id.as_raw() {
+            debug!("Drop a stale response {}", packet);
+            return
+        }
+        state.response = Some(packet);
+        cell.arrived.notify_one();
+        #[cfg(feature = "async")]
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn closed(&self) -> Option<Error> {
+        self.closed.lock().clone()
+    }
+
+    /// Fails the calls waiting for the responses, and the calls after this, with `reason`.
+    /// Returns the number of the calls that were waiting.
+    fn close(&self, reason: Error) -> usize {
+        {
+            let mut closed = self.closed.lock();
+            if closed.is_some() {
+                return 0
+            }
+            *closed = Some(reason);
+        }
+        let mut waiting = 0;
+        for cell in &self.cells {
+            #[cfg_attr(not(feature = "async"), allow(unused_mut))]
+            let mut state = cell.state.lock();
+            if state.awaiting != SlotId::empty().as_raw() && state.response.is_none() {
+                waiting += 1;
+            }
+            cell.arrived.notify_all();
+            #[cfg(feature = "async")]
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+        // Calls waiting for a free slot fail as well.
+        let mut _free = self.free.lock();
+        self.freed.notify_all();
+        #[cfg(feature = "async")]
+        for waker in _free.wakers.drain(..) {
+            waker.wake();
+        }
+        waiting
+    }
 }
 
 #[derive(Debug)]
 pub struct Client {
-    call_slots: Arc<Queue<CallSlot>>,
+    slots: Arc<Slots>,
+    /// The timeout of a call which doesn't specify it.
+    call_timeout: Option<time::Duration>,
     ipc_send: Sender<Packet>,
-    receiver_thread: Option<thread::JoinHandle<()>>,
+    receiver_thread: Mutex<Option<thread::JoinHandle<()>>>,
     joined_event_receiver: Receiver<()>,
 }
 
 impl Client {
-    pub fn new(ipc_send: Sender<Packet>, ipc_recv: Receiver<Packet>) -> Self {
+    pub fn new(config: &Config, ipc_send: Sender<Packet>, ipc_recv: Receiver<Packet>) -> Self {
         let (joined_event_sender, joined_event_receiver) = bounded(1);
-        let callslot_size = SlotId::new(CALLSLOT_SIZE);
-        let call_slots = Arc::new(Queue::new(callslot_size.as_usize()));
-        let mut to_slot_receivers = Vec::with_capacity(callslot_size.as_usize());
-
-        for i in 0..callslot_size.as_raw() {
-            let (send_to_slot_recv, recv_for_slot) = bounded(1);
-            call_slots
-                .push(CallSlot {
-                    id: SlotId::new(i),
-                    response: recv_for_slot,
-                })
-                .expect("Client does not call close");
-            to_slot_receivers.push(send_to_slot_recv);
-        }
+        let slots = Arc::new(Slots::new(config.call_slots));
+        let slots_ = Arc::clone(&slots);
 
         Client {
-            call_slots,
+            slots,
+            call_timeout: config.call_timeout,
             ipc_send,
-            receiver_thread: Some(
+            receiver_thread: Mutex::new(Some(
                 thread::Builder::new()
                     .spawn(move || {
-                        if let Err(RecvError) = receive_loop(ipc_recv, to_slot_receivers) {
+                        if let Err(RecvError) = receive_loop(ipc_recv, &slots_) {
                             // Multiplexer is closed
                         }
+                        slots_.close(Error::ConnectionLost);
                         joined_event_sender.send(()).unwrap();
                     })
                     .unwrap(),
-            ),
+            )),
             joined_event_receiver,
         }
     }
 
-    pub fn call(&self, packet: PacketView) -> Packet {
-        let slot = self.call_slots.pop(Some(TIMEOUT)).expect("Too many calls on port");
+    pub fn call(&self, mut packet: Packet, timeout: Option<time::Duration>) -> Result<Packet, Error> {
+        let deadline = timeout.or(self.call_timeout).map(|timeout| time::Instant::now() + timeout);
+        let slot = self.slots.acquire(deadline)?;
 
-        let packet = {
-            let mut packet = packet.to_owned();
-            packet.set_slot(slot.id.into_request());
-            packet
+        packet.set_slot(slot);
+        let response_packet = match self.ipc_send.send(packet) {
+            Ok(()) => self.slots.wait(slot, deadline),
+            Err(_) => Err(Error::ConnectionLost),
         };
 
-        self.ipc_send.send(packet).expect("port::Client::call is called after mulitplexer is dropped");
-        let response_packet = slot.response.recv().expect("counterparty send is managed by client");
+        self.slots.release(slot);
+        into_result(response_packet?)
+    }
+
+    /// The call is made when the future is first polled, and a free slot is available.
+    /// It is not bounded by the timeouts. Use the timer of the async runtime instead.
+    #[cfg(feature = "async")]
+    pub fn call_async(&self, packet: Packet) -> ResponseFuture {
+        ResponseFuture {
+            slots: Arc::clone(&self.slots),
+            ipc_send: self.ipc_send.clone(),
+            packet: Some(packet),
+            slot: None,
+            waiting: None,
+        }
+    }
+
+    /// Sends the request without a slot. The counterparty won't respond to it.
+    pub fn send_oneway(&self, mut packet: Packet) -> Result<(), Error> {
+        if let Some(error) = self.slots.closed() {
+            return Err(error)
+        }
+        packet.set_oneway();
+        self.ipc_send.send(packet).map_err(|_| Error::ConnectionLost)
+    }
 
-        self.call_slots.push(slot).expect("Client does not close the queue");
-        response_packet
+    /// Fails the calls waiting for the responses with `Error::ShuttingDown`, and the calls after this as well.
+    /// Then tells the counterparty that this side is shutting down. Returns the number of the failed calls.
+    pub fn close(&self) -> usize {
+        let failed = self.slots.close(Error::ShuttingDown);
+        let mut goodbye = Packet::new_request(0, GOODBYE, &[]);
+        goodbye.set_oneway();
+        if self.ipc_send.send(goodbye).is_err() {
+            debug!("Multiplexer is dropped before saying goodbye");
+        }
+        failed
     }
 
-    pub fn shutdown(&mut self) {
-        match self.joined_event_receiver.recv_timeout(time::Duration::from_millis(100)) {
-            Err(Timeout) => {
-                panic!(
-                    "There may be a deadlock or misuse of Client. Call Client::shutdown after Multiplexer::shutdown"
-                );
+    /// Waits for the receive thread until the deadline. Call this after Multiplexer::shutdown.
+    /// The thread is detached if it doesn't finish by then, and this returns false.
+    pub fn shutdown(&self, deadline: time::Instant) -> bool {
+        let receiver_thread = self.receiver_thread.lock().take().expect("Client is shut down only once");
+        match self.joined_event_receiver.recv_timeout(deadline.saturating_duration_since(time::Instant::now())) {
+            Ok(()) => {
+                receiver_thread.join().unwrap();
+                true
             }
-            Err(Disconnected) => {
-                panic!("Maybe receive_loop thread panics");
+            Err(err) => {
+                warn!("Client receiver thread is detached: {}", err);
+                false
             }
-            Ok(_) => {}
         }
-        self.receiver_thread.take().unwrap().join().unwrap();
     }
 }
 
 impl Drop for Client {
     fn drop(&mut self) {
-        assert!(self.receiver_thread.is_none(), "Please call shutdown");
+        assert!(self.receiver_thread.lock().is_none(), "Please call shutdown");
     }
 }
 
-fn receive_loop(ipc_recv: Receiver<Packet>, to_slot_receivers: Vec<Sender<Packet>>) -> Result<(), RecvError> {
+/// Response of an async call. Dropping it before the response gives the slot back.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct ResponseFuture {
+    slots: Arc<Slots>,
+    ipc_send: Sender<Packet>,
+    /// The request, until it is sent.
+    packet: Option<Packet>,
+    slot: Option<SlotId>,
+    /// The waker that is queued for a free slot.
+    waiting: Option<Waker>,
+}
+
+#[cfg(feature = "async")]
+impl Future for ResponseFuture {
+    type Output = Result<Packet, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let slot = match self.slot {
+            Some(slot) => slot,
+            None => {
+                if let Some(error) = self.slots.closed() {
+                    return Poll::Ready(Err(error))
+                }
+                let slot = match self.slots.try_acquire(cx.waker()) {
+                    Some(slot) => slot,
+                    None => {
+                        self.waiting = Some(cx.waker().clone());
+                        return Poll::Pending
+                    }
+                };
+                self.waiting = None;
+                self.slot = Some(slot);
+                let mut packet = self.packet.take().expect("Request is sent only once");
+                packet.set_slot(slot);
+                if self.ipc_send.send(packet).is_err() {
+                    return Poll::Ready(Err(Error::ConnectionLost))
+                }
+                slot
+            }
+        };
+        let response = match self.slots.poll_response(slot, cx.waker()) {
+            Poll::Ready(response) => response,
+            Poll::Pending => return Poll::Pending,
+        };
+        self.slots.release(slot);
+        self.slot = None;
+        Poll::Ready(response.and_then(into_result))
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for ResponseFuture {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            self.slots.release(slot);
+        }
+        if let Some(waker) = self.waiting.take() {
+            self.slots.stop_waiting(&waker);
+        }
+    }
+}
+
+/// An error response carries the `Error` that the counterparty sent.
+fn into_result(response_packet: Packet) -> Result<Packet, Error> {
+    if response_packet.view().is_error() {
+        return Err(serde_cbor::from_slice(response_packet.data())
+            .unwrap_or_else(|err| Error::DeserializationFailed(err.to_string())))
+    }
+    Ok(response_packet)
+}
+
+fn receive_loop(ipc_recv: Receiver<Packet>, slots: &Slots) -> Result<(), RecvError> {
     loop {
-        let packet = ipc_recv.recv()?;
-        let slot_id = packet.view().slot();
-        to_slot_receivers[slot_id.as_usize()]
-            .send(packet)
-            .expect("Slot receivers are managed in Client. Client must be dropped after this thread");
+        slots.deliver(ipc_recv.recv()?);
     }
 }
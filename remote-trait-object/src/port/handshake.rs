@@ -0,0 +1,221 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Each side advertises the ids of the methods that it dispatches, as the first packet of the connection.
+//! The caller then sends the id that the counterparty expects, even if the two are built with different ids.
+//! Likewise, the calls to the service objects of a side are in the format that the side advertises.
+
+use crate::codec::Format;
+use crate::forwarder::HANDSHAKE;
+use crate::packet::Packet;
+use crate::service::id::{IdMap, MID_REG};
+use crate::service::MethodId;
+use crate::Error;
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize)]
+pub struct Advertisement {
+    /// (trait name, method name, id)
+    pub methods: Vec<(String, String, MethodId)>,
+    /// The format that the service objects of the sender take, unless their trait specifies it.
+    #[serde(default)]
+    pub format: Format,
+}
+
+/// The handshake packet of this side, which dispatches the methods with the ids of `id_map`.
+pub fn advertisement(id_map: &IdMap, format: Format) -> Packet {
+    let methods = MID_REG
+        .iter()
+        .map(|(trait_name, method_name, ..)| {
+            let id = id_map.method_id(trait_name, method_name).expect("It is in the registry");
+            ((*trait_name).to_owned(), (*method_name).to_owned(), id)
+        })
+        .collect();
+    let data = serde_cbor::to_vec(&Advertisement {
+        methods,
+        format,
+    })
+    .expect("Method ids are always serializable");
+    Packet::new_request(0, HANDSHAKE, &data)
+}
+
+type MethodIdTable = HashMap<String, HashMap<String, MethodId>>;
+
+#[derive(Debug)]
+struct Peer {
+    methods: MethodIdTable,
+    format: Format,
+}
+
+/// Method ids and the format of the counterparty. It is filled by the handshake packet,
+/// which the context waits for when it is built.
+#[derive(Debug)]
+pub struct PeerMethods {
+    handshake_recv: Receiver<Packet>,
+    peer: Mutex<Option<Result<Peer, Error>>>,
+    timeout: Option<Duration>,
+}
+
+impl PeerMethods {
+    pub fn new(handshake_recv: Receiver<Packet>, timeout: Option<Duration>) -> Self {
+        Self {
+            handshake_recv,
+            peer: Mutex::new(None),
+            timeout,
+        }
+    }
+
+    /// Receives the advertisement of the counterparty, unless it is already received.
+    /// A failure is kept, so every later call fails the same.
+    pub fn handshake(&self) -> Result<(), Error> {
+        self.with_peer(|_| Ok(()))
+    }
+
+    pub fn method_id(&self, trait_name: &str, method_name: &str) -> Result<MethodId, Error> {
+        self.with_peer(|peer| {
+            peer.methods.get(trait_name).and_then(|methods| methods.get(method_name)).copied().ok_or_else(|| {
+                Error::Incompatible {
+                    trait_name: trait_name.to_owned(),
+                    method_name: method_name.to_owned(),
+                }
+            })
+        })
+    }
+
+    pub fn format(&self) -> Result<Format, Error> {
+        self.with_peer(|peer| Ok(peer.format))
+    }
+
+    fn with_peer<T>(&self, f: impl FnOnce(&Peer) -> Result<T, Error>) -> Result<T, Error> {
+        let mut peer = self.peer.lock();
+        if peer.is_none() {
+            *peer = Some(self.receive());
+        }
+        f(peer.as_ref().expect("It is filled above").as_ref().map_err(Clone::clone)?)
+    }
+
+    fn receive(&self) -> Result<Peer, Error> {
+        let packet = match self.timeout {
+            Some(timeout) => self.handshake_recv.recv_timeout(timeout).map_err(|err| match err {
+                RecvTimeoutError::Timeout => {
+                    Error::HandshakeFailed(format!("Counterparty didn't advertise its methods in {:?}", timeout))
+                }
+                RecvTimeoutError::Disconnected => Error::ConnectionLost,
+            })?,
+            None => self.handshake_recv.recv().map_err(|_| Error::ConnectionLost)?,
+        };
+        let advertisement: Advertisement = serde_cbor::from_slice(packet.data())
+            .map_err(|err| Error::HandshakeFailed(format!("Invalid advertisement: {}", err)))?;
+        if !advertisement.format.is_enabled() {
+            return Err(Error::HandshakeFailed(format!(
+                "Counterparty takes {}, which is not enabled",
+                advertisement.format
+            )))
+        }
+        let mut methods = MethodIdTable::new();
+        for (trait_name, method_name, id) in advertisement.methods {
+            methods.entry(trait_name).or_default().insert(method_name, id);
+        }
+        Ok(Peer {
+            methods,
+            format: advertisement.format,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::channel::bounded;
+
+    #[test]
+    fn translate() {
+        let (handshake_send, handshake_recv) = bounded(1);
+        let peer = PeerMethods::new(handshake_recv, None);
+        let data = serde_cbor::to_vec(&Advertisement {
+            methods: vec![("A".to_owned(), "f".to_owned(), 1234)],
+            format: Format::Cbor,
+        })
+        .unwrap();
+        handshake_send.send(Packet::new_request(0, HANDSHAKE, &data)).unwrap();
+        assert_eq!(peer.method_id("A", "f"), Ok(1234));
+        assert_eq!(
+            peer.method_id("A", "g"),
+            Err(Error::Incompatible {
+                trait_name: "A".to_owned(),
+                method_name: "g".to_owned()
+            })
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn format_from_advertisement() {
+        let (handshake_send, handshake_recv) = bounded(1);
+        let peer = PeerMethods::new(handshake_recv, None);
+        let data = serde_cbor::to_vec(&Advertisement {
+            methods: Vec::new(),
+            format: Format::Json,
+        })
+        .unwrap();
+        handshake_send.send(Packet::new_request(0, HANDSHAKE, &data)).unwrap();
+        assert_eq!(peer.format(), Ok(Format::Json));
+    }
+
+    #[test]
+    fn format_of_older_peer() {
+        #[derive(Serialize)]
+        struct OldAdvertisement {
+            methods: Vec<(String, String, MethodId)>,
+        }
+
+        let (handshake_send, handshake_recv) = bounded(1);
+        let peer = PeerMethods::new(handshake_recv, None);
+        let data = serde_cbor::to_vec(&OldAdvertisement {
+            methods: Vec::new(),
+        })
+        .unwrap();
+        handshake_send.send(Packet::new_request(0, HANDSHAKE, &data)).unwrap();
+        assert_eq!(peer.format(), Ok(Format::Cbor));
+    }
+
+    #[test]
+    fn no_advertisement() {
+        let (handshake_send, handshake_recv) = bounded(1);
+        let peer = PeerMethods::new(handshake_recv, Some(Duration::from_millis(10)));
+        assert!(matches!(peer.handshake(), Err(Error::HandshakeFailed(_))));
+        // A late advertisement doesn't make it succeed.
+        let data = serde_cbor::to_vec(&Advertisement {
+            methods: vec![("A".to_owned(), "f".to_owned(), 1234)],
+            format: Format::Cbor,
+        })
+        .unwrap();
+        handshake_send.send(Packet::new_request(0, HANDSHAKE, &data)).unwrap();
+        assert!(matches!(peer.method_id("A", "f"), Err(Error::HandshakeFailed(_))));
+    }
+
+    #[test]
+    fn connection_lost() {
+        let (handshake_send, handshake_recv) = bounded(1);
+        let peer = PeerMethods::new(handshake_recv, None);
+        drop(handshake_send);
+        assert_eq!(peer.method_id("A", "f"), Err(Error::ConnectionLost));
+    }
+}
@@ -15,28 +15,99 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::types::Handler;
-use crate::packet::Packet;
+use crate::context::Config;
+use crate::forwarder::is_port_request;
+use crate::interceptor::{self, Call, Intercepted, Interceptor, Side};
+use crate::packet::{Packet, PacketBuilder};
 use crate::queue::{PopError, Queue};
-use crossbeam::channel::RecvTimeoutError::{Disconnected, Timeout};
+#[cfg(feature = "async")]
+use crate::service::async_dispatch::{block_on, BoxFuture};
+use crate::Error;
 use crossbeam::channel::{self, Receiver, Sender};
+use parking_lot::{Condvar, Mutex};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
 use std::thread;
 use std::time;
+#[cfg(feature = "async")]
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Runs the calls to async service objects, instead of the server threads.
+/// An implementation would give the task to the runtime, like `tokio::spawn`.
+#[cfg(feature = "async")]
+pub trait Executor: Send + Sync {
+    fn spawn(&self, task: BoxFuture<()>);
+}
+
+#[cfg(feature = "async")]
+impl fmt::Debug for dyn Executor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Executor")
+    }
+}
+
+#[derive(Debug, Default)]
+struct InFlightState {
+    count: usize,
+    closed: bool,
+}
+
+/// Requests from the counterparty that are accepted, but not answered yet.
+#[derive(Debug, Default)]
+struct InFlight {
+    state: Mutex<InFlightState>,
+    finished: Condvar,
+}
+
+impl InFlight {
+    /// None if the server doesn't accept requests anymore.
+    fn accept(self: &Arc<Self>) -> Option<Accepted> {
+        let mut state = self.state.lock();
+        if state.closed {
+            return None
+        }
+        state.count += 1;
+        Some(Accepted(Arc::clone(self)))
+    }
+}
+
+/// The request is answered when this is dropped.
+struct Accepted(Arc<InFlight>);
+
+impl Drop for Accepted {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.count -= 1;
+        if state.count == 0 {
+            self.0.finished.notify_all();
+        }
+    }
+}
 
 pub struct Server {
     receiver_thread: Option<thread::JoinHandle<()>>,
     joined_event_receiver: Receiver<()>,
+    in_flight: Arc<InFlight>,
 }
 
 impl Server {
-    pub fn new<H>(handler: Arc<H>, ipc_send: Sender<Packet>, ipc_recv: Receiver<Packet>) -> Self
+    pub fn new<H>(config: &Config, handler: Arc<H>, ipc_send: Sender<Packet>, ipc_recv: Receiver<Packet>) -> Self
     where
         H: Handler + Send + 'static, {
         let (joined_event_sender, joined_event_receiver) = channel::bounded(1);
+        let config = config.clone();
+        let in_flight = Arc::new(InFlight::default());
+        let in_flight_ = Arc::clone(&in_flight);
         let receiver_thread = thread::Builder::new()
             .name("port server receiver".into())
             .spawn(move || {
-                receiver(handler, ipc_send, ipc_recv);
+                receiver(config, handler, ipc_send, ipc_recv, in_flight_);
                 joined_event_sender.send(()).expect("Server will be dropped after thread is joined");
             })
             .unwrap();
@@ -44,21 +115,41 @@ impl Server {
         Server {
             receiver_thread: Some(receiver_thread),
             joined_event_receiver,
+            in_flight,
         }
     }
 
-    pub fn shutdown(mut self) {
-        match self.joined_event_receiver.recv_timeout(time::Duration::from_millis(500)) {
-            Err(Timeout) => {
-                panic!("There may be a deadlock or misuse of Server. Call Server::shutdown when ipc_recv is closed");
-            }
-            Err(Disconnected) => {
-                panic!("Maybe receiver thread panics");
+    /// The requests after this are answered with `Error::ShuttingDown`.
+    pub fn close(&self) {
+        self.in_flight.state.lock().closed = true;
+    }
+
+    /// Waits until the deadline for the accepted requests to be answered.
+    /// Returns the number of the requests that are not answered yet.
+    pub fn drain(&self, deadline: time::Instant) -> usize {
+        let mut state = self.in_flight.state.lock();
+        while state.count > 0 {
+            if self.in_flight.finished.wait_until(&mut state, deadline).timed_out() {
+                break
             }
-            Ok(_) => {}
         }
+        state.count
+    }
 
-        self.receiver_thread.take().unwrap().join().unwrap();
+    /// Waits for the threads until the deadline. Call this when ipc_recv is closed.
+    /// The threads are detached if they don't finish by then, and this returns false.
+    pub fn shutdown(mut self, deadline: time::Instant) -> bool {
+        let receiver_thread = self.receiver_thread.take().unwrap();
+        match self.joined_event_receiver.recv_timeout(deadline.saturating_duration_since(time::Instant::now())) {
+            Ok(()) => {
+                receiver_thread.join().unwrap();
+                true
+            }
+            Err(err) => {
+                warn!("Server threads are detached: {}", err);
+                false
+            }
+        }
     }
 }
 
@@ -68,14 +159,65 @@ impl Drop for Server {
     }
 }
 
-fn receiver<H>(handler: Arc<H>, ipc_send: Sender<Packet>, ipc_recv: Receiver<Packet>)
-where
+fn receiver<H>(
+    config: Config,
+    handler: Arc<H>,
+    ipc_send: Sender<Packet>,
+    ipc_recv: Receiver<Packet>,
+    in_flight: Arc<InFlight>,
+) where
     H: Handler + 'static, {
-    let received_packets = Arc::new(Queue::new(100));
-    let joiners = create_handler_threads(handler, ipc_send, Arc::clone(&received_packets));
+    let received_packets = Arc::new(Queue::new(config.server_queue_size));
+    let joiners = create_handler_threads(
+        config.server_threads,
+        Arc::clone(&handler),
+        &config.interceptors,
+        ipc_send.clone(),
+        Arc::clone(&received_packets),
+    );
 
     while let Ok(request) = ipc_recv.recv() {
-        received_packets.push(request).expect("Queue will close after this loop");
+        let accepted = match in_flight.accept() {
+            Some(accepted) => accepted,
+            None => {
+                trace!("Reject a request while shutting down {}", request);
+                send(&ipc_send, respond(&request, Err(Error::ShuttingDown), None));
+                continue
+            }
+        };
+        #[cfg(feature = "async")]
+        {
+            if let Some(executor) = config.executor.as_ref().filter(|_| handler.is_async(request.view())) {
+                let request = Arc::new(request);
+                // The interceptors see the request before the service object does, and may reject it.
+                let intercepted =
+                    panic::catch_unwind(AssertUnwindSafe(|| intercept(&config.interceptors, &*handler, &request)))
+                        .unwrap_or_else(|payload| Err(remote_panic(&request, &*payload)));
+                let intercepted = match intercepted {
+                    Ok(intercepted) => intercepted,
+                    Err(error) => {
+                        send(&ipc_send, respond(&request, Err(error), None));
+                        continue
+                    }
+                };
+                match panic::catch_unwind(AssertUnwindSafe(|| handler.handle_async(&request))) {
+                    Ok(Some(response)) => {
+                        executor.spawn(respond_async(request, response, intercepted, ipc_send.clone(), accepted));
+                    }
+                    // The service object is deleted after `is_async`.
+                    Ok(None) => {
+                        let error = Error::ObjectNotFound(request.view().object_id());
+                        send(&ipc_send, respond(&request, Err(error), intercepted));
+                    }
+                    Err(payload) => {
+                        let error = remote_panic(&request, &*payload);
+                        send(&ipc_send, respond(&request, Err(error), intercepted));
+                    }
+                }
+                continue
+            }
+        }
+        received_packets.push((Arc::new(request), accepted)).expect("Queue will close after this loop");
     }
     // ipc_recv is closed.
 
@@ -86,27 +228,49 @@ where
 }
 
 fn create_handler_threads<H>(
+    threads: usize,
     handler: Arc<H>,
+    interceptors: &[Arc<dyn Interceptor>],
     ipc_send: Sender<Packet>,
-    received_packets: Arc<Queue<Packet>>,
+    received_packets: Arc<Queue<(Arc<Packet>, Accepted)>>,
 ) -> Vec<thread::JoinHandle<()>>
 where
     H: Handler + 'static, {
     let mut joins = Vec::new();
 
-    fn handler_loop<H: Handler>(handler: Arc<H>, ipc_send: Sender<Packet>, received_packets: Arc<Queue<Packet>>) {
+    fn handler_loop<H: Handler>(
+        handler: Arc<H>,
+        interceptors: Vec<Arc<dyn Interceptor>>,
+        ipc_send: Sender<Packet>,
+        received_packets: Arc<Queue<(Arc<Packet>, Accepted)>>,
+    ) {
         loop {
-            let request = match received_packets.pop(None) {
+            // It is dropped after the response is sent.
+            let (request, _accepted) = match received_packets.pop(None) {
                 Ok(packet) => packet,
                 Err(PopError::Timeout) => unreachable!(),
                 Err(PopError::QueueClosed) => break,
             };
 
             trace!("Packet received in Port Server {}", request);
-            let response = handler.handle(request.view());
-            trace!("Handler result in Port Server {:?}", response);
-            let mut response_packet = Packet::new_response_from_request(request.view());
-            response_packet.append_data(&response);
+            let mut intercepted = None;
+            // A panic of the service object or an interceptor must not kill this thread,
+            // or the caller would wait forever.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                intercepted = intercept(&interceptors, &*handler, &request)?;
+                // There is no executor, so this thread waits for the future.
+                #[cfg(feature = "async")]
+                if let Some(response) = handler.handle_async(&request) {
+                    return block_on(response)
+                }
+                let mut response = PacketBuilder::new();
+                handler.handle(request.view(), &mut response).map(|()| response)
+            }))
+            .unwrap_or_else(|payload| Err(remote_panic(&request, &*payload)));
+            let response_packet = match respond(&request, result, intercepted) {
+                Some(response_packet) => response_packet,
+                None => continue,
+            };
             if let Err(err) = ipc_send.send(response_packet) {
                 trace!("Multiplexer is dropped while sending a packet {:?}", err.into_inner());
                 break
@@ -114,18 +278,124 @@ where
         }
     }
 
-    // FIXME: get thread count from config
-    for i in 0..4 {
+    for i in 0..threads {
         let packet_queue_ = Arc::clone(&received_packets);
         let ipc_send_ = ipc_send.clone();
         let handler_ = Arc::clone(&handler);
+        let interceptors_ = interceptors.to_vec();
 
         let join_handle = thread::Builder::new()
             .name(format!("port server send {}", i))
-            .spawn(move || handler_loop(handler_, ipc_send_, packet_queue_))
+            .spawn(move || handler_loop(handler_, interceptors_, ipc_send_, packet_queue_))
             .unwrap();
         joins.push(join_handle);
     }
 
     joins
 }
+
+/// Lets the interceptors see the request, unless it is for the port itself.
+/// A rejected request is answered with the error, without the call.
+fn intercept<H: Handler>(
+    interceptors: &[Arc<dyn Interceptor>],
+    handler: &H,
+    request: &Packet,
+) -> Result<Option<Intercepted>, Error> {
+    let view = request.view();
+    if interceptors.is_empty() || is_port_request(view.method()) {
+        return Ok(None)
+    }
+    let call = Call {
+        side: Side::Server,
+        object_id: view.object_id(),
+        method: view.method(),
+        name: handler.method_name(request.view()),
+    };
+    interceptor::intercept(interceptors, call, view.data())
+}
+
+/// The response to the request, unless it is one-way.
+/// The interceptors that let the request go see the result first.
+fn respond(request: &Packet, result: Result<PacketBuilder, Error>, intercepted: Option<Intercepted>) -> Option<Packet> {
+    if let Some(intercepted) = intercepted {
+        intercepted.finish(result.as_ref().map(|response| response.data()));
+    }
+    if request.view().is_oneway() {
+        if let Err(error) = result {
+            warn!("One-way handler failed in Port Server: {}", error);
+        }
+        return None
+    }
+    Some(match result {
+        Ok(response) => {
+            trace!("Handler result in Port Server {:?}", response.data());
+            response.into_response(request.view())
+        }
+        Err(error) => {
+            warn!("Handler failed in Port Server {}", error);
+            let mut response = PacketBuilder::new();
+            serde_cbor::to_writer(&mut response, &error).expect("Error is always serializable");
+            response.into_error_response(request.view())
+        }
+    })
+}
+
+fn remote_panic(request: &Packet, payload: &(dyn Any + Send)) -> Error {
+    Error::RemotePanic {
+        object_id: request.view().object_id(),
+        method: request.view().method(),
+        message: panic_message(payload),
+    }
+}
+
+/// The task given to the executor, which sends the response when the call finishes.
+/// The interceptors have let the request go already, and see the result in `intercepted`.
+#[cfg(feature = "async")]
+fn respond_async(
+    request: Arc<Packet>,
+    response: BoxFuture<Result<PacketBuilder, Error>>,
+    intercepted: Option<Intercepted>,
+    ipc_send: Sender<Packet>,
+    accepted: Accepted,
+) -> BoxFuture<()> {
+    Box::pin(async move {
+        let result = CatchUnwind(response).await.unwrap_or_else(|payload| Err(remote_panic(&request, &*payload)));
+        send(&ipc_send, respond(&request, result, intercepted));
+        drop(accepted);
+    })
+}
+
+fn send(ipc_send: &Sender<Packet>, response_packet: Option<Packet>) {
+    if let Some(response_packet) = response_packet {
+        if let Err(err) = ipc_send.send(response_packet) {
+            trace!("Multiplexer is dropped while sending a packet {:?}", err.into_inner());
+        }
+    }
+}
+
+/// Catches a panic of the service object while it is polled, as the server threads do.
+#[cfg(feature = "async")]
+struct CatchUnwind<T>(BoxFuture<T>);
+
+#[cfg(feature = "async")]
+impl<T> Future for CatchUnwind<T> {
+    type Output = thread::Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.0.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Unknown panic".to_owned()
+    }
+}
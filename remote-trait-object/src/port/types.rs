@@ -14,17 +14,42 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::packet::PacketView;
+#[cfg(feature = "async")]
+use crate::packet::Packet;
+use crate::packet::{PacketBuilder, PacketView};
+#[cfg(feature = "async")]
+use crate::service::async_dispatch::BoxFuture;
+use crate::Error;
+#[cfg(feature = "async")]
+use std::sync::Arc;
 
 pub trait Handler: Send + Sync {
-    fn handle(&self, input: PacketView) -> Vec<u8>;
+    /// Writes the data of the response into `response`, which becomes the response packet as it is.
+    /// An error is sent to the caller instead.
+    fn handle(&self, input: PacketView, response: &mut PacketBuilder) -> Result<(), Error>;
+    /// (trait name, method name) of the request, for the interceptors.
+    fn method_name(&self, _input: PacketView) -> Option<(&'static str, &'static str)> {
+        None
+    }
+    /// The response as a future, if the request is for an async service object.
+    /// The future decodes the arguments and calls the method, so this returns without doing either.
+    /// Otherwise the request is given to `handle`.
+    #[cfg(feature = "async")]
+    fn handle_async(&self, _request: &Arc<Packet>) -> Option<BoxFuture<Result<PacketBuilder, Error>>> {
+        None
+    }
+    /// Whether `handle_async` takes the request, so that the interceptors can see it before.
+    #[cfg(feature = "async")]
+    fn is_async(&self, _input: PacketView) -> bool {
+        false
+    }
 }
 
 impl<F> Handler for F
 where
-    F: Fn(PacketView) -> Vec<u8> + Send + Sync,
+    F: Fn(PacketView, &mut PacketBuilder) -> Result<(), Error> + Send + Sync,
 {
-    fn handle(&self, input: PacketView) -> Vec<u8> {
-        self(input)
+    fn handle(&self, input: PacketView, response: &mut PacketBuilder) -> Result<(), Error> {
+        self(input, response)
     }
 }
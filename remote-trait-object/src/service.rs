@@ -14,14 +14,21 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+#[cfg(feature = "async")]
+pub mod async_dispatch;
 pub mod id;
 pub mod remote;
 pub mod serde_support;
 
+use crate::codec::Format;
 use crate::forwarder::ServiceObjectId;
+use crate::packet::PacketBuilder;
 use crate::port::Port;
+use crate::Error;
 use serde::{Deserialize, Serialize};
+use std::io::Write;
 use std::sync::{Arc, Weak};
+use std::time::Duration;
 
 pub type MethodId = u32;
 
@@ -31,42 +38,63 @@ pub type MethodId = u32;
 #[derive(PartialEq, Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct HandleToExchange(pub(crate) ServiceObjectId);
 
-// TODO: Remove this
-impl HandleToExchange {
-    pub fn new_singleton() -> Self {
-        HandleToExchange(0)
-    }
-}
-
 /// Remote service will carry this.
 #[derive(Debug)]
 pub struct Handle {
     pub id: ServiceObjectId,
     pub port: Weak<dyn Port>,
+    /// Overrides the default of the context, but not the timeout of the method.
+    pub timeout: Option<Duration>,
+    /// The format of the trait. If None, the counterparty tells it in the handshake.
+    pub format: Option<Format>,
 }
 
 impl Handle {
     /// You should not call this! This is for the macro.
+    /// The port counts each handle, and deletes the service object when the last one is dropped.
     pub fn careful_new(imported_id: HandleToExchange, port: Weak<dyn Port>) -> Self {
+        if let Some(port) = port.upgrade() {
+            port.add_ref(imported_id.0);
+        }
         Handle {
             id: imported_id.0,
             port,
+            timeout: None,
+            format: None,
         }
     }
+
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// You should not call this! This is for the macro.
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = Some(format);
+        self
+    }
 }
 
 /// Exporter sides's interface to the service object. This will be implemented
 /// by each service trait's unique wrapper in the macro
 pub trait Dispatch: Send + Sync {
-    fn dispatch_and_call(&self, method: MethodId, args: &[u8]) -> Vec<u8>;
+    /// The return value is serialized into `response`.
+    /// Arguments that can't be deserialized fail the call, rather than the service object.
+    fn dispatch_and_call(&self, method: MethodId, args: &[u8], response: &mut PacketBuilder) -> Result<(), Error>;
+    /// (trait name, method name) of the method id that this dispatches, for the interceptors.
+    fn method_name(&self, _method: MethodId) -> Option<(&'static str, &'static str)> {
+        None
+    }
 }
 
 impl<F> Dispatch for F
 where
     F: Fn(MethodId, &[u8]) -> Vec<u8> + Send + Sync,
 {
-    fn dispatch_and_call(&self, method: MethodId, args: &[u8]) -> Vec<u8> {
-        self(method, args)
+    fn dispatch_and_call(&self, method: MethodId, args: &[u8], response: &mut PacketBuilder) -> Result<(), Error> {
+        response.write_all(&self(method, args)).expect("Writing to PacketBuilder never fails");
+        Ok(())
     }
 }
 
@@ -74,7 +102,13 @@ where
 /// These tratis will be implement by `dyn ServiceTrait` where `T = dyn ServiceTrait` as well.
 /// Macro will implement this trait with the target(expanding) service trait.
 pub trait ImportService<T: ?Sized + Service> {
-    fn import(port: Weak<dyn Port>, handle: HandleToExchange) -> Arc<T>;
+    fn import(port: Weak<dyn Port>, handle: HandleToExchange) -> Arc<T> {
+        Self::import_with_timeout(port, handle, None)
+    }
+
+    /// Every call through the imported object waits at most `timeout`, instead of the default of the context.
+    /// A method with `#[timeout_ms]` still uses its own timeout.
+    fn import_with_timeout(port: Weak<dyn Port>, handle: HandleToExchange, timeout: Option<Duration>) -> Arc<T>;
 }
 
 pub trait ExportService<T: ?Sized + Service> {
@@ -95,6 +129,14 @@ macro_rules! import_service {
         let port = $context.get_port();
         <dyn $service_trait as remote_trait_object::ImportService<dyn $service_trait>>::import(port, $handle)
     }};
+    ($service_trait: path, $context: expr, $handle: expr, $timeout: expr) => {{
+        let port = $context.get_port();
+        <dyn $service_trait as remote_trait_object::ImportService<dyn $service_trait>>::import_with_timeout(
+            port,
+            $handle,
+            Some($timeout),
+        )
+    }};
 }
 
 /// All service trait must implement this.
@@ -0,0 +1,98 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Service objects whose methods return futures.
+//! They are driven by the executor of the context, instead of occupying a server thread for the whole call.
+
+use super::serde_support::port_thread_local;
+use super::{HandleToExchange, MethodId};
+use crate::codec::Format;
+use crate::packet::{Packet, PacketBuilder};
+use crate::port::Port;
+use crate::Error;
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Async version of `Dispatch`. The future owns the request, and deserializes the arguments from it
+/// when it is first polled, so that neither the deserialization nor the method holds up the caller of this.
+pub trait AsyncDispatch: Send + Sync {
+    fn dispatch_and_call_async(
+        &self,
+        method: MethodId,
+        request: Arc<Packet>,
+    ) -> BoxFuture<Result<PacketBuilder, Error>>;
+    /// (trait name, method name) of the method id that this dispatches, for the interceptors.
+    fn method_name(&self, _method: MethodId) -> Option<(&'static str, &'static str)> {
+        None
+    }
+}
+
+/// Implemented by the macro for `dyn FooAsync` of `#[service(async_dispatch)]`.
+pub trait ExportAsyncService<T: ?Sized> {
+    fn export_async(port: Weak<dyn Port>, object: Arc<T>) -> HandleToExchange;
+}
+
+/// Deserializes the arguments in the request and calls the method with them, when the future is first polled.
+/// Both the arguments and the result are (de)serialized with the port of the call, which `SArc` needs.
+/// You should not call this! This is for the macro.
+pub fn serve<A, R, F, C>(format: Format, request: Arc<Packet>, call: C) -> BoxFuture<Result<PacketBuilder, Error>>
+where
+    A: DeserializeOwned,
+    R: serde::Serialize,
+    F: Future<Output = R> + Send + 'static,
+    C: FnOnce(A) -> F + Send + 'static, {
+    let port = port_thread_local::get_port();
+    Box::pin(async move {
+        let future = {
+            let _port_guard = port_thread_local::set_port_guarded(port.clone());
+            call(format.decode(request.data())?)
+        };
+        drop(request);
+        let result = future.await;
+        let _port_guard = port_thread_local::set_port_guarded(port);
+        let mut response = PacketBuilder::new();
+        format.encode_into(&result, &mut response)?;
+        Ok(response)
+    })
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs the future on this thread. This is how a server thread calls an async service object
+/// when the context has no executor.
+pub(crate) fn block_on<T>(future: BoxFuture<T>) -> T {
+    let mut future = future;
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output
+        }
+        thread::park();
+    }
+}
@@ -15,9 +15,16 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::MethodId;
+use crate::forwarder::RESERVED_METHOD_ID_START;
 use linkme::distributed_slice;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+#[cfg(feature = "id-map-file")]
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
 
 pub const ID_ORDERING: std::sync::atomic::Ordering = std::sync::atomic::Ordering::SeqCst;
 pub type MethodIdAtomic = std::sync::atomic::AtomicU32;
@@ -32,33 +39,224 @@ pub type MethodIdAtomic = std::sync::atomic::AtomicU32;
 // Also you can skip calling this, then the method id will be set up for default value
 // decided by the order of declaration.
 type MethodIdentifierSetter = fn(id: MethodId);
+type MethodIdentifierGetter = fn() -> MethodId;
 #[distributed_slice]
-pub static MID_REG: [(&'static str, &'static str, MethodIdentifierSetter)] = [..];
+pub static MID_REG: [(&'static str, &'static str, MethodIdentifierSetter, MethodIdentifierGetter)] = [..];
 
 /// This will be provided by the user who cares the compatability between already-compiled service traits.
-#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+/// It can be given to `setup_identifiers` for the whole process, or to `ContextBuilder::id_map` for a context.
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone, Default)]
 pub struct IdMap {
-    // This is system-wide; All module will get same ones
     pub method_map: Option<HashMap<(String, String), MethodId>>,
 }
 
+/// Form of `IdMap` in a file, since a map with tuple keys can't be written in JSON nor TOML.
+/// It is sorted so that the file is stable to be checked in.
+type IdTable = BTreeMap<String, BTreeMap<String, MethodId>>;
+
+impl IdMap {
+    /// The id of the method, or the default if this map doesn't have it.
+    pub fn method_id(&self, trait_name: &str, method_name: &str) -> Option<MethodId> {
+        self.method_map
+            .as_ref()
+            .and_then(|map| map.get(&(trait_name.to_owned(), method_name.to_owned())))
+            .copied()
+            .or_else(|| default_method_id(trait_name, method_name))
+    }
+
+    /// The current ids of all the methods in this binary.
+    pub fn from_registry() -> Self {
+        let method_map = MID_REG
+            .iter()
+            .map(|(trait_name, method_name, _, getter)| {
+                (((*trait_name).to_owned(), (*method_name).to_owned()), getter())
+            })
+            .collect();
+        IdMap {
+            method_map: Some(method_map),
+        }
+    }
+
+    /// Reads a JSON or TOML file, by the extension of the path.
+    #[cfg(feature = "id-map-file")]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, IdMapError> {
+        let path = path.as_ref();
+        let format = IdMapFormat::of(path)?;
+        let text = fs::read_to_string(path)?;
+        let table: IdTable = match format {
+            IdMapFormat::Json => serde_json::from_str(&text).map_err(|err| IdMapError::Parse(err.to_string()))?,
+            IdMapFormat::Toml => toml::from_str(&text).map_err(|err| IdMapError::Parse(err.to_string()))?,
+        };
+        let method_map = table
+            .into_iter()
+            .flat_map(|(trait_name, methods)| {
+                methods.into_iter().map(move |(method_name, id)| ((trait_name.clone(), method_name), id))
+            })
+            .collect();
+        Ok(IdMap {
+            method_map: Some(method_map),
+        })
+    }
+
+    /// Writes a JSON or TOML file, by the extension of the path.
+    #[cfg(feature = "id-map-file")]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), IdMapError> {
+        let path = path.as_ref();
+        let format = IdMapFormat::of(path)?;
+        let table = self.to_table();
+        let text = match format {
+            IdMapFormat::Json => {
+                serde_json::to_string_pretty(&table).map_err(|err| IdMapError::Serialize(err.to_string()))?
+            }
+            IdMapFormat::Toml => toml::to_string(&table).map_err(|err| IdMapError::Serialize(err.to_string()))?,
+        };
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Changes from `self` to `newer`.
+    pub fn diff(&self, newer: &IdMap) -> IdMapDiff {
+        let old = self.to_table();
+        let new = newer.to_table();
+        let mut diff = IdMapDiff::default();
+        for (trait_name, methods) in &old {
+            for (method_name, old_id) in methods {
+                match new.get(trait_name).and_then(|methods| methods.get(method_name)) {
+                    None => diff.removed.push((trait_name.clone(), method_name.clone(), *old_id)),
+                    Some(new_id) if new_id != old_id => {
+                        diff.renumbered.push((trait_name.clone(), method_name.clone(), *old_id, *new_id))
+                    }
+                    Some(_) => (),
+                }
+            }
+        }
+        for (trait_name, methods) in &new {
+            for (method_name, new_id) in methods {
+                if old.get(trait_name).and_then(|methods| methods.get(method_name)).is_none() {
+                    diff.added.push((trait_name.clone(), method_name.clone(), *new_id));
+                }
+            }
+        }
+        diff
+    }
+
+    fn to_table(&self) -> IdTable {
+        let mut table = IdTable::new();
+        for ((trait_name, method_name), id) in self.method_map.iter().flatten() {
+            table.entry(trait_name.clone()).or_default().insert(method_name.clone(), *id);
+        }
+        table
+    }
+}
+
+#[cfg(feature = "id-map-file")]
+enum IdMapFormat {
+    Json,
+    Toml,
+}
+
+#[cfg(feature = "id-map-file")]
+impl IdMapFormat {
+    fn of(path: &Path) -> Result<Self, IdMapError> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(IdMapFormat::Json),
+            Some("toml") => Ok(IdMapFormat::Toml),
+            _ => Err(IdMapError::UnknownFormat(path.to_owned())),
+        }
+    }
+}
+
+/// Difference between two `IdMap`s, each of which is sorted by the trait and the method name.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct IdMapDiff {
+    /// (trait name, method name, id)
+    pub added: Vec<(String, String, MethodId)>,
+    /// (trait name, method name, id)
+    pub removed: Vec<(String, String, MethodId)>,
+    /// (trait name, method name, old id, new id)
+    pub renumbered: Vec<(String, String, MethodId, MethodId)>,
+}
+
+impl IdMapDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.renumbered.is_empty()
+    }
+
+    /// A peer built with the older map can still call every method of the newer one.
+    /// The added methods are fine, since the older peer doesn't know them.
+    pub fn is_compatible(&self) -> bool {
+        self.removed.is_empty() && self.renumbered.is_empty()
+    }
+}
+
+impl fmt::Display for IdMapDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (trait_name, method_name, id) in &self.added {
+            writeln!(f, "+ {}::{} = {}", trait_name, method_name, id)?;
+        }
+        for (trait_name, method_name, id) in &self.removed {
+            writeln!(f, "- {}::{} = {}", trait_name, method_name, id)?;
+        }
+        for (trait_name, method_name, old_id, new_id) in &self.renumbered {
+            writeln!(f, "~ {}::{} = {} -> {}", trait_name, method_name, old_id, new_id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "id-map-file")]
+#[derive(Debug)]
+pub enum IdMapError {
+    Io(io::Error),
+    /// The path must end with either `.json` or `.toml`.
+    UnknownFormat(PathBuf),
+    Parse(String),
+    Serialize(String),
+}
+
+#[cfg(feature = "id-map-file")]
+impl fmt::Display for IdMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdMapError::Io(err) => write!(f, "Failed to access the id map file: {}", err),
+            IdMapError::UnknownFormat(path) => write!(f, "Unknown format of the id map file {}", path.display()),
+            IdMapError::Parse(msg) => write!(f, "Failed to parse the id map: {}", msg),
+            IdMapError::Serialize(msg) => write!(f, "Failed to serialize the id map: {}", msg),
+        }
+    }
+}
+
+#[cfg(feature = "id-map-file")]
+impl std::error::Error for IdMapError {}
+
+#[cfg(feature = "id-map-file")]
+impl From<io::Error> for IdMapError {
+    fn from(err: io::Error) -> Self {
+        IdMapError::Io(err)
+    }
+}
+
 /// This is supposed to be called only once during the entire lifetime of the process.
 /// However it is ok to call multiple times if the IdMap is identical, especially in the
 /// tests where each test share that static id list
 /// # Examples
 /// ```
 /// use remote_trait_object::macro_env::*;
+/// use std::collections::HashMap;
 /// #[allow(non_upper_case_globals)]
 /// static ID_METHOD_MyTrait_mymethod: MethodIdAtomic = MethodIdAtomic::new(1);
 /// #[linkme::distributed_slice(MID_REG)]
 /// #[allow(non_upper_case_globals)]
-/// static ID_METHOD_ENTRY_MyTrait_mymethod: (&'static str, &'static str, fn(id: MethodId)) =
-///     ("MyTrait", "mymethod", id_method_setter_MyTrait_mymethod);
+/// static ID_METHOD_ENTRY_MyTrait_mymethod: (&'static str, &'static str, fn(id: MethodId), fn() -> MethodId) =
+///     ("MyTrait", "mymethod", id_method_setter_MyTrait_mymethod, id_method_getter_MyTrait_mymethod);
 /// #[allow(non_snake_case)]
 /// fn id_method_setter_MyTrait_mymethod(id: MethodId) {
 ///     ID_METHOD_MyTrait_mymethod.store(id, ID_ORDERING);
 /// }
-/// #[test]
+/// #[allow(non_snake_case)]
+/// fn id_method_getter_MyTrait_mymethod() -> MethodId {
+///     ID_METHOD_MyTrait_mymethod.load(ID_ORDERING)
+/// }
 /// fn setup() {
 ///     let id_map: HashMap<(String, String), MethodId> =
 ///         [(("MyTrait".to_owned(), "mymethod".to_owned()), 123)].iter().cloned().collect();
@@ -68,12 +266,13 @@ pub struct IdMap {
 ///     setup_identifiers(&id_map);
 ///     assert_eq!(ID_METHOD_MyTrait_mymethod.load(ID_ORDERING), 123);
 /// }
+/// setup();
 /// ```
 pub fn setup_identifiers(descriptor: &IdMap) {
     // distributed_slices integrity test
     {
         let mut bucket: HashSet<(String, String)> = HashSet::new();
-        for (ident1, ident2, _) in MID_REG {
+        for (ident1, ident2, ..) in MID_REG {
             bucket.insert(((*ident1).to_owned(), (*ident2).to_owned()));
         }
         assert_eq!(
@@ -86,10 +285,117 @@ pub fn setup_identifiers(descriptor: &IdMap) {
 
     // method ids have default values decided by the order, so it is ok to leave them in an ordinary case.
     if let Some(map) = descriptor.method_map.as_ref() {
-        for (trait_name, method_name, setter) in MID_REG {
+        for (trait_name, method_name, setter, _) in MID_REG {
             setter(
                 *map.get(&((*trait_name).to_owned(), (*method_name).to_owned())).expect("Invalid handle descriptor"),
             );
         }
     }
+
+    check_collision(|trait_name, method_name| {
+        default_method_id(trait_name, method_name).expect("It is in the registry")
+    });
+}
+
+/// Methods are dispatched by the id within a trait, so two methods of a trait must not share an id.
+/// This might happen with the ids derived from the hash, or with a wrong IdMap.
+pub(crate) fn check_collision(id_of: impl Fn(&str, &str) -> MethodId) {
+    let mut bucket: HashMap<(&str, MethodId), &str> = HashMap::new();
+    for (trait_name, method_name, ..) in MID_REG {
+        let id = id_of(trait_name, method_name);
+        if let Some(other) = bucket.insert((*trait_name, id), *method_name) {
+            panic!("Methods {} and {} of {} have the same id {}", other, method_name, trait_name, id);
+        }
+    }
+}
+
+/// The default id of a method in the hash scheme of the macro.
+/// `key` is the trait, the method and the signature, while `module_path` tells apart the traits of the same name.
+/// It is evaluated at compile time, and must not change between the builds.
+pub const fn hash_method_id(module_path: &str, key: &str) -> MethodId {
+    let hash = fnv1a(0x811c_9dc5, module_path.as_bytes());
+    let hash = fnv1a(hash, b"::");
+    fnv1a(hash, key.as_bytes()) % RESERVED_METHOD_ID_START
+}
+
+const fn fnv1a(mut hash: u32, bytes: &[u8]) -> u32 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash = (hash ^ bytes[i] as u32).wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    hash
+}
+
+/// The id of the method decided by the macro or `setup_identifiers`, which is shared by the whole process.
+pub fn default_method_id(trait_name: &str, method_name: &str) -> Option<MethodId> {
+    MID_REG.iter().find(|(t, m, ..)| *t == trait_name && *m == method_name).map(|(.., getter)| getter())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id_map(entries: &[(&str, &str, MethodId)]) -> IdMap {
+        IdMap {
+            method_map: Some(
+                entries
+                    .iter()
+                    .map(|(trait_name, method_name, id)| (((*trait_name).to_owned(), (*method_name).to_owned()), *id))
+                    .collect(),
+            ),
+        }
+    }
+
+    #[cfg(feature = "id-map-file")]
+    #[test]
+    fn save_and_load() {
+        let map = id_map(&[("A", "f", 70), ("A", "g", 71), ("B", "f", 1234)]);
+        let dir = std::env::temp_dir();
+        for extension in &["json", "toml"] {
+            let path = dir.join(format!("rto-id-map-{}.{}", std::process::id(), extension));
+            map.save(&path).unwrap();
+            assert_eq!(IdMap::load(&path).unwrap(), map);
+            fs::remove_file(&path).unwrap();
+        }
+        assert!(matches!(map.save(dir.join("id-map.yaml")), Err(IdMapError::UnknownFormat(_))));
+    }
+
+    #[cfg(feature = "id-map-file")]
+    #[test]
+    fn load_toml() {
+        let path = std::env::temp_dir().join(format!("rto-id-map-load-{}.toml", std::process::id()));
+        fs::write(&path, "[A]\nf = 70\ng = 71\n").unwrap();
+        assert_eq!(IdMap::load(&path).unwrap(), id_map(&[("A", "f", 70), ("A", "g", 71)]));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn diff() {
+        let old = id_map(&[("A", "f", 70), ("A", "g", 71), ("B", "f", 70)]);
+        let new = id_map(&[("A", "f", 70), ("A", "g", 72), ("A", "h", 73)]);
+        let diff = old.diff(&new);
+        assert_eq!(diff.added, vec![("A".to_owned(), "h".to_owned(), 73)]);
+        assert_eq!(diff.removed, vec![("B".to_owned(), "f".to_owned(), 70)]);
+        assert_eq!(diff.renumbered, vec![("A".to_owned(), "g".to_owned(), 71, 72)]);
+        assert!(!diff.is_compatible());
+        assert!(old.diff(&old).is_empty());
+        assert!(old.diff(&id_map(&[("A", "f", 70), ("A", "g", 71), ("B", "f", 70), ("C", "f", 1)])).is_compatible());
+    }
+
+    #[test]
+    fn stable_hash_id() {
+        let id = hash_method_id("my_crate::service", "A::g(&self,)");
+        assert!(id < RESERVED_METHOD_ID_START);
+        assert_ne!(id, hash_method_id("my_crate::other", "A::g(&self,)"));
+        // It must not change between the builds
+        assert_eq!(id, 555_067_533);
+    }
+
+    #[test]
+    fn registry() {
+        let map = IdMap::from_registry();
+        assert_eq!(map.method_map.as_ref().unwrap().len(), MID_REG.len());
+        assert!(IdMap::default().diff(&map).is_compatible());
+    }
 }
@@ -14,28 +14,177 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::service::{Handle, MethodId};
-use crate::Packet;
+use crate::codec::Format;
+use crate::interceptor::{self, Call, Intercepted, Side};
+use crate::port::Port;
+use crate::service::Handle;
+use crate::{Error, Packet, PacketBuilder};
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::time::Duration;
 
 impl Handle {
     /// This method is the core of Handle, which serves as a "call stub" for the service trait's method.
     /// It carries out user's remote call in a generic way.
     /// Invoking this method is role of the macro, by putting appropriate instantiation of this generic
     /// for each service trait's method, according to the method signature of each.
-    pub fn call<S: serde::Serialize, D: serde::de::DeserializeOwned>(&self, method: MethodId, args: &S) -> D {
-        super::serde_support::port_thread_local::set_port(self.port.clone());
-        let args = serde_cbor::to_vec(args).unwrap();
-        let packet = Packet::new_request(self.id, method, &args);
-        let response = self.port.upgrade().unwrap().call(packet.view());
-        let result = serde_cbor::from_slice(response.data()).unwrap();
-        super::serde_support::port_thread_local::remove_port();
-        result
+    /// `method` is (trait name, method name), whose id is given by the port.
+    /// `timeout` is given by the method. If None, the timeout of the handle is used,
+    /// and then the default of the context.
+    /// It panics if the call fails. Use `try_call` to handle the failure.
+    pub fn call<S: serde::Serialize, D: serde::de::DeserializeOwned>(
+        &self,
+        method: (&'static str, &'static str),
+        args: &S,
+        timeout: Option<Duration>,
+    ) -> D {
+        self.try_call(method, args, timeout).unwrap_or_else(|err| panic!("Remote call failed: {}", err))
+    }
+
+    /// Same as `call`, but returns the failure of the transport or the serialization as an `Error`.
+    pub fn try_call<S: serde::Serialize, D: serde::de::DeserializeOwned>(
+        &self,
+        method: (&'static str, &'static str),
+        args: &S,
+        timeout: Option<Duration>,
+    ) -> Result<D, Error> {
+        let _port_guard = super::serde_support::port_thread_local::set_port_guarded(self.port.clone());
+        self.call_with_port(method, args, timeout.or(self.timeout))
+    }
+
+    /// Sends the request of a `#[oneway]` method, without waiting for it to be handled.
+    /// It panics if the request can't be sent. Use `try_call_oneway` to handle the failure.
+    pub fn call_oneway<S: serde::Serialize>(&self, method: (&'static str, &'static str), args: &S) {
+        self.try_call_oneway(method, args).unwrap_or_else(|err| panic!("Remote call failed: {}", err))
+    }
+
+    /// Same as `call_oneway`, but returns the failure to send the request as an `Error`.
+    /// The failure of the counterparty to handle it is never known.
+    pub fn try_call_oneway<S: serde::Serialize>(
+        &self,
+        method: (&'static str, &'static str),
+        args: &S,
+    ) -> Result<(), Error> {
+        let _port_guard = super::serde_support::port_thread_local::set_port_guarded(self.port.clone());
+        self.port.upgrade().ok_or(Error::PortDropped).and_then(|port| {
+            let packet = self.request(&*port, self.format(&*port)?, method, args)?;
+            let intercepted = self.intercept(&*port, method, &packet)?;
+            let result = port.call_oneway(packet);
+            if let Some(intercepted) = intercepted {
+                intercepted.finish(result.as_ref().map(|()| &[][..]));
+            }
+            result
+        })
+    }
+
+    /// Async version of `try_call`. The arguments are serialized before this returns,
+    /// and the returned future doesn't borrow the handle.
+    /// It holds a reference to the service object instead, so the handle may be dropped before it is polled.
+    /// The call is not bounded by the timeouts. Use the timer of your async runtime instead.
+    #[cfg(feature = "async")]
+    pub fn call_async<S: serde::Serialize, D: serde::de::DeserializeOwned + 'static>(
+        &self,
+        method: (&'static str, &'static str),
+        args: &S,
+    ) -> impl Future<Output = Result<D, Error>> + Send + 'static {
+        let response = {
+            let _port_guard = super::serde_support::port_thread_local::set_port_guarded(self.port.clone());
+            self.port.upgrade().ok_or(Error::PortDropped).and_then(|port| {
+                let format = self.format(&*port)?;
+                let packet = self.request(&*port, format, method, args)?;
+                let intercepted = self.intercept(&*port, method, &packet)?;
+                Ok((port.call_async(packet), format, intercepted))
+            })
+        };
+        let handle = self.clone();
+        async move {
+            let (response, format, intercepted) = response?;
+            let response = response.await;
+            if let Some(intercepted) = intercepted {
+                intercepted.finish(response.as_ref().map(|response| response.data()));
+            }
+            let response = response?;
+            let _port_guard = super::serde_support::port_thread_local::set_port_guarded(handle.port.clone());
+            format.decode(response.data())
+        }
+    }
+
+    /// The format of the trait, or the one that the counterparty takes.
+    fn format(&self, port: &dyn Port) -> Result<Format, Error> {
+        match self.format {
+            Some(format) => Ok(format),
+            None => port.peer_format(),
+        }
+    }
+
+    fn request<S: serde::Serialize>(
+        &self,
+        port: &dyn Port,
+        format: Format,
+        method: (&'static str, &'static str),
+        args: &S,
+    ) -> Result<Packet, Error> {
+        let method = port.method_id(method.0, method.1)?;
+        let mut builder = PacketBuilder::new();
+        format.encode_into(args, &mut builder)?;
+        Ok(builder.into_request(self.id, method))
+    }
+
+    /// Lets the interceptors of the port see the request, which they may reject.
+    fn intercept(
+        &self,
+        port: &dyn Port,
+        method: (&'static str, &'static str),
+        request: &Packet,
+    ) -> Result<Option<Intercepted>, Error> {
+        let request = request.view();
+        let call = Call {
+            side: Side::Client,
+            object_id: self.id,
+            method: request.method(),
+            name: Some(method),
+        };
+        interceptor::intercept(port.interceptors(), call, request.data())
+    }
+
+    fn call_with_port<S: serde::Serialize, D: serde::de::DeserializeOwned>(
+        &self,
+        method: (&'static str, &'static str),
+        args: &S,
+        timeout: Option<Duration>,
+    ) -> Result<D, Error> {
+        let port = self.port.upgrade().ok_or(Error::PortDropped)?;
+        let format = self.format(&*port)?;
+        let packet = self.request(&*port, format, method, args)?;
+        let intercepted = self.intercept(&*port, method, &packet)?;
+        let response = port.call(packet, timeout);
+        if let Some(intercepted) = intercepted {
+            intercepted.finish(response.as_ref().map(|response| response.data()));
+        }
+        format.decode(response?.data())
+    }
+}
+
+impl Clone for Handle {
+    /// The port counts the clone as another handle to the same service object.
+    fn clone(&self) -> Self {
+        if let Some(port) = self.port.upgrade() {
+            port.add_ref(self.id);
+        }
+        Handle {
+            id: self.id,
+            port: self.port.clone(),
+            timeout: self.timeout,
+            format: self.format,
+        }
     }
 }
 
 impl Drop for Handle {
     /// Dropping handle will be signaled to the exporter, so that it can remove the service object as well.
     fn drop(&mut self) {
-        self.port.upgrade().unwrap().delete_request(self.id);
+        if let Some(port) = self.port.upgrade() {
+            port.delete_request(self.id);
+        }
     }
 }
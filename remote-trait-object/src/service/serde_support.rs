@@ -20,12 +20,16 @@ use serde::ser::{Serialize, Serializer};
 
 pub struct SArc<T: ?Sized + Service> {
     value: std::cell::Cell<Option<Arc<T>>>,
+    /// Some formats like bincode serialize a value twice, first to measure its size.
+    /// The object is exported only once, and this is serialized the second time.
+    exported: std::cell::Cell<Option<HandleToExchange>>,
 }
 
 impl<T: ?Sized + Service> SArc<T> {
     pub fn new(value: Arc<T>) -> Self {
         SArc {
             value: std::cell::Cell::new(Some(value)),
+            exported: std::cell::Cell::new(None),
         }
     }
 
@@ -69,14 +73,34 @@ pub mod port_thread_local {
             k.try_borrow_mut().unwrap().pop().unwrap();
         })
     }
+
+    /// Removes the port when dropped, so that the stack stays consistent even if the service object panics.
+    pub struct PortGuard;
+
+    pub fn set_port_guarded(port: Weak<dyn Port>) -> PortGuard {
+        set_port(port);
+        PortGuard
+    }
+
+    impl Drop for PortGuard {
+        fn drop(&mut self) {
+            remove_port();
+        }
+    }
 }
 
 impl<T: ?Sized + Service + ExportService<T>> Serialize for SArc<T> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer, {
-        let service = self.take();
-        let handle = T::export(port_thread_local::get_port(), service);
+        let handle = match self.exported.get() {
+            Some(handle) => handle,
+            None => {
+                let handle = T::export(port_thread_local::get_port(), self.take());
+                self.exported.set(Some(handle));
+                handle
+            }
+        };
         handle.serialize(serializer)
     }
 }
@@ -115,7 +139,7 @@ mod tests {
             pub handle_to_exchange: HandleToExchange,
         }
         impl FooImpl {
-            pub fn new(handle: u32) -> Self {
+            pub fn new(handle: u64) -> Self {
                 Self {
                     handle_to_exchange: HandleToExchange(handle),
                 }
@@ -162,6 +186,7 @@ mod tests {
         use super::mock;
         use crate::{HandleToExchange, ImportService, Port, Service};
         use std::sync::{Arc, Weak};
+        use std::time::Duration;
 
         trait Foo: Service {
             fn get_handle_to_exchange(&self) -> HandleToExchange;
@@ -176,7 +201,11 @@ mod tests {
         }
         impl Service for FooImpl {}
         impl ImportService<dyn Foo> for dyn Foo {
-            fn import(_port: Weak<dyn Port>, handle: HandleToExchange) -> Arc<dyn Foo> {
+            fn import_with_timeout(
+                _port: Weak<dyn Port>,
+                handle: HandleToExchange,
+                _timeout: Option<Duration>,
+            ) -> Arc<dyn Foo> {
                 Arc::new(FooImpl {
                     handle_to_exchange: handle,
                 })
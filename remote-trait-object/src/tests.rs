@@ -19,17 +19,23 @@ mod complex_trait;
 use crate as remote_trait_object;
 use remote_trait_object_macro as rto_macro;
 
-use crate::forwarder::ServiceObjectId;
-use crate::packet::{Packet, PacketView};
+use crate::forwarder::{object_generation, object_index, ServiceObjectId, HANDSHAKE, LOOKUP};
+use crate::ipc::{intra, IpcRecv, IpcSend};
+use crate::packet::{Packet, PacketBuilder, PacketView};
+use crate::port::handshake::Advertisement;
 use crate::port::*;
+use crate::service::id::{setup_identifiers, IdMap, ID_ORDERING};
 use crate::service::*;
+use crate::{Context, ContextBuilder, Error, Format};
 use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 
 struct TestDispatchMap {
-    last_id: u32,
-    map: HashMap<u32, Arc<dyn Dispatch>>,
+    last_id: ServiceObjectId,
+    map: HashMap<ServiceObjectId, Arc<dyn Dispatch>>,
 }
 
 impl TestDispatchMap {
@@ -40,17 +46,17 @@ impl TestDispatchMap {
         }
     }
 
-    fn insert(&mut self, service_object: Arc<dyn Dispatch>) -> u32 {
+    fn insert(&mut self, service_object: Arc<dyn Dispatch>) -> ServiceObjectId {
         self.last_id += 1;
         self.map.insert(self.last_id, service_object);
         self.last_id
     }
 
-    fn get_cloned(&mut self, id: u32) -> Arc<dyn Dispatch> {
-        Arc::clone(&self.map.get(&id).unwrap())
+    fn get_cloned(&mut self, id: ServiceObjectId) -> Arc<dyn Dispatch> {
+        Arc::clone(self.map.get(&id).unwrap())
     }
 
-    fn remove(&mut self, id: u32) {
+    fn remove(&mut self, id: ServiceObjectId) {
         self.map.remove(&id);
     }
 
@@ -82,15 +88,18 @@ impl std::fmt::Debug for TestPort {
 }
 
 impl Port for TestPort {
-    fn call(&self, packet: PacketView) -> Packet {
+    fn call(&self, packet: Packet, _timeout: Option<Duration>) -> Result<Packet, Error> {
+        let packet = packet.view();
         let object_id = packet.object_id();
         let dispatcher = self.dispatch_map.lock().get_cloned(object_id);
-        let response = dispatcher.dispatch_and_call(packet.method(), packet.data());
-        let mut response_packet = Packet::new_response_from_request(packet);
-        response_packet.append_data(&response);
-        response_packet
+        let mut response = PacketBuilder::new();
+        dispatcher.dispatch_and_call(packet.method(), packet.data(), &mut response)?;
+        Ok(response.into_response(packet))
     }
 
+    // Every object is imported only once in these tests.
+    fn add_ref(&self, _id: ServiceObjectId) {}
+
     fn delete_request(&self, id: ServiceObjectId) {
         self.dispatch_map.lock().remove(id);
     }
@@ -136,14 +145,14 @@ fn macro1() {
     let object = Arc::new(MyObject {
         mul: 4,
     }) as Arc<dyn Service1>;
-    let dispatcher = Arc::new(Service1Dispatcher {
-        object,
-    }) as Arc<dyn Dispatch>;
+    let dispatcher = Arc::new(Service1Dispatcher::new(object)) as Arc<dyn Dispatch>;
     let handle = port.register(dispatcher);
     let remote = Service1Remote {
         handle: Handle {
             port: port_weak,
             id: handle.0,
+            timeout: None,
+            format: None,
         },
     };
 
@@ -153,3 +162,218 @@ fn macro1() {
     drop(remote);
     assert_eq!(port.register_len(), 0);
 }
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum DivError {
+    DivisionByZero,
+}
+
+#[rto_macro::service]
+pub trait Service2: Service {
+    fn checked_div(&self, a: i32, b: i32) -> Result<Result<i32, DivError>, remote_trait_object::Error>;
+}
+
+struct Divider;
+
+impl Service for Divider {}
+
+impl Service2 for Divider {
+    fn checked_div(&self, a: i32, b: i32) -> Result<Result<i32, DivError>, Error> {
+        Ok(a.checked_div(b).ok_or(DivError::DivisionByZero))
+    }
+}
+
+#[test]
+fn fallible_call() {
+    let port = Arc::new(TestPort::new());
+    let port_weak = Arc::downgrade(&port);
+
+    let object = Arc::new(Divider) as Arc<dyn Service2>;
+    let handle = port.register(Arc::new(Service2Dispatcher::new(object)));
+    let remote = Service2Remote {
+        handle: Handle::careful_new(handle, port_weak),
+    };
+
+    assert_eq!(remote.checked_div(6, 3), Ok(Ok(2)));
+    // The error of the service is delivered as it is, apart from the failure of the call.
+    assert_eq!(remote.checked_div(6, 0), Ok(Err(DivError::DivisionByZero)));
+    drop(port);
+    assert_eq!(remote.checked_div(6, 3), Err(Error::PortDropped));
+}
+
+#[rto_macro::service(id_scheme = "hash")]
+pub trait Service3: Service {
+    fn add(&self, a: i32, b: i32) -> i32;
+    fn sub(&self, a: i32, b: i32) -> i32;
+}
+
+struct Calculator;
+
+impl Service for Calculator {}
+
+impl Service3 for Calculator {
+    fn add(&self, a: i32, b: i32) -> i32 {
+        a + b
+    }
+
+    fn sub(&self, a: i32, b: i32) -> i32 {
+        a - b
+    }
+}
+
+#[test]
+fn hash_method_id() {
+    // Ids don't depend on the order of declaration.
+    assert_ne!(ID_METHOD_Service3_add.load(ID_ORDERING), 70);
+    assert_ne!(ID_METHOD_Service3_sub.load(ID_ORDERING), 71);
+    setup_identifiers(&IdMap {
+        method_map: None,
+    });
+
+    let port = Arc::new(TestPort::new());
+    let object = Arc::new(Calculator) as Arc<dyn Service3>;
+    let handle = port.register(Arc::new(Service3Dispatcher::new(object)));
+    let remote = Service3Remote {
+        handle: Handle::careful_new(handle, Arc::downgrade(&port) as Weak<dyn Port>),
+    };
+    assert_eq!(remote.add(3, 2), 5);
+    assert_eq!(remote.sub(3, 2), 1);
+}
+
+#[test]
+fn handshake_translates_method_id() {
+    let ((send1, recv1), (send2, recv2)) = intra::pair();
+    // The counterparty is built with another id for `add`, and without `sub`.
+    let advertisement = Advertisement {
+        methods: vec![("Service3".to_owned(), "add".to_owned(), 1000)],
+        format: Format::Cbor,
+    };
+    send2.send(Packet::new_request(0, HANDSHAKE, &serde_cbor::to_vec(&advertisement).unwrap()).buffer());
+    let context = Context::new(send1, recv1).unwrap();
+
+    let handshake = recv2.recv(None).unwrap();
+    assert_eq!(PacketView::parse(&handshake).unwrap().method(), HANDSHAKE);
+
+    let counterparty = std::thread::spawn(move || {
+        let request = recv2.recv(None).unwrap();
+        let request = PacketView::parse(&request).unwrap();
+        assert_eq!(request.object_id(), 7);
+        assert_eq!(request.method(), 1000);
+        let mut response = PacketBuilder::new();
+        serde_cbor::to_writer(&mut response, &5).unwrap();
+        send2.send(response.into_response(request).buffer());
+    });
+
+    let handle = Handle::careful_new(HandleToExchange(7), context.get_port());
+    assert_eq!(handle.try_call::<_, i32>(("Service3", "add"), &(3, 2), None), Ok(5));
+    assert_eq!(
+        handle.try_call::<_, i32>(("Service3", "sub"), &(3, 2), None),
+        Err(Error::Incompatible {
+            trait_name: "Service3".to_owned(),
+            method_name: "sub".to_owned()
+        })
+    );
+    counterparty.join().unwrap();
+
+    context.disable_garbage_collection();
+    drop(handle);
+}
+
+#[test]
+fn handshake_on_build() {
+    // The counterparty is connected, but never sends the handshake.
+    let ((send1, recv1), (_send2, _recv2)) = intra::pair();
+    let context = ContextBuilder::new().handshake_timeout(Some(Duration::from_millis(10))).build(send1, recv1);
+    assert!(matches!(context, Err(Error::HandshakeFailed(_))));
+
+    let ((send1, recv1), (send2, _recv2)) = intra::pair();
+    send2.send(Packet::new_request(0, HANDSHAKE, b"Not an advertisement").buffer());
+    assert!(matches!(Context::new(send1, recv1), Err(Error::HandshakeFailed(_))));
+}
+
+#[test]
+fn context_id_map() {
+    let id_map = IdMap {
+        method_map: Some([(("Service3".to_owned(), "add".to_owned()), 5000)].iter().cloned().collect()),
+    };
+    let (exporter, importer) = ContextBuilder::new().id_map(id_map).build_pair_with(ContextBuilder::new()).unwrap();
+
+    let exporter_port = exporter.get_port().upgrade().unwrap();
+    assert_eq!(exporter_port.local_method_id("Service3", "add"), 5000);
+    // The default for the methods that are not in the map
+    assert_eq!(exporter_port.local_method_id("Service3", "sub"), ID_METHOD_Service3_sub.load(ID_ORDERING));
+    assert_ne!(ID_METHOD_Service3_add.load(ID_ORDERING), 5000);
+
+    let handle = <dyn Service3 as ExportService<dyn Service3>>::export(exporter.get_port(), Arc::new(Calculator));
+    let calculator = <dyn Service3 as ImportService<dyn Service3>>::import(importer.get_port(), handle);
+    assert_eq!(calculator.add(3, 2), 5);
+    assert_eq!(calculator.sub(3, 2), 1);
+    assert_eq!(importer.get_port().upgrade().unwrap().method_id("Service3", "add"), Ok(5000));
+
+    drop(calculator);
+    drop(exporter_port);
+    drop(importer);
+    drop(exporter);
+}
+
+#[test]
+fn stale_handle() {
+    let (exporter, importer) = Context::pair().unwrap();
+    let export = |port: Weak<dyn Port>| {
+        <dyn Service3 as ExportService<dyn Service3>>::export(port, Arc::new(Calculator) as Arc<dyn Service3>)
+    };
+
+    // Ids are not bounded by a fixed pool.
+    let handles: Vec<_> = (0..200).map(|_| export(exporter.get_port())).collect();
+    let old = handles[0];
+    let calculators: Vec<_> = handles
+        .into_iter()
+        .map(|handle| <dyn Service3 as ImportService<dyn Service3>>::import(importer.get_port(), handle))
+        .collect();
+    assert_eq!(calculators[199].add(3, 2), 5);
+    drop(calculators);
+
+    // The index of the deleted object is reused with the next generation.
+    let new = export(exporter.get_port());
+    assert_eq!(object_index(new.0), object_index(old.0));
+    assert_eq!(object_generation(new.0), object_generation(old.0) + 1);
+
+    let stale = Handle::careful_new(old, importer.get_port());
+    assert_eq!(stale.try_call::<_, i32>(("Service3", "add"), &(3, 2), None), Err(Error::ObjectNotFound(old.0)));
+    drop(stale);
+    let calculator = <dyn Service3 as ImportService<dyn Service3>>::import(importer.get_port(), new);
+    assert_eq!(calculator.add(3, 2), 5);
+    drop(calculator);
+}
+
+#[test]
+fn invalid_lookup() {
+    let (exporter, importer) = Context::pair().unwrap();
+    exporter.publish::<dyn Service3>("calculator", Arc::new(Calculator));
+
+    // The name is not in CBOR, but the exporter keeps serving.
+    let port = importer.get_port().upgrade().unwrap();
+    let result = port.call(Packet::new_request(0, LOOKUP, &[0xff]), None);
+    assert!(matches!(result, Err(Error::DeserializationFailed(_))));
+    drop(port);
+    let calculator = importer.lookup::<dyn Service3>("calculator").unwrap();
+    assert_eq!(calculator.add(3, 2), 5);
+    drop(calculator);
+}
+
+#[test]
+fn invalid_arguments() {
+    let (exporter, importer) = Context::pair().unwrap();
+    exporter.publish::<dyn Service3>("calculator", Arc::new(Calculator));
+    let handle = importer.lookup_handle("calculator").unwrap();
+
+    // The arguments are not in CBOR, but the service object keeps serving.
+    let port = importer.get_port().upgrade().unwrap();
+    let add = port.method_id("Service3", "add").unwrap();
+    let result = port.call(Packet::new_request(handle.0, add, &[0xff]), None);
+    assert!(matches!(result, Err(Error::DeserializationFailed(_))));
+    drop(port);
+    let calculator = <dyn Service3 as ImportService<dyn Service3>>::import(importer.get_port(), handle);
+    assert_eq!(calculator.add(3, 2), 5);
+    drop(calculator);
+}